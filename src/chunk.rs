@@ -1,4 +1,7 @@
-use crate::object::Value;
+use crate::{
+    memory::{Handle, GC},
+    object::{Class, Closure, Function, Value},
+};
 
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -40,9 +43,35 @@ pub enum Op {
     Class,
     Inherit,
     Method,
+    SmallInt,
+    Yield,
+    SetGlobalPop,
+    SetLocalPop,
+    Stringify,
+    Dup,
+    GreaterEqual,
+    LessEqual,
+    GetThis,
+    Modulo,
+    InvokeThis,
+    AddNum,
+    AddStr,
+    BuildList,
+    Index,
+    SetIndex,
+    MethodDynamic,
+    JumpLong,
+    JumpIfFalseLong,
+    LoopLong,
+    DeepEqual,
+    StaticMethod,
+    PopN,
+    TailCall,
+    Undefined,
+    Power,
 }
 
-const OP_COUNT: usize = Op::Method as usize + 1;
+pub const OP_COUNT: usize = Op::Power as usize + 1;
 const OP_CODES: [Op; OP_COUNT] = [
     Op::Constant,
     Op::Nil,
@@ -81,24 +110,202 @@ const OP_CODES: [Op; OP_COUNT] = [
     Op::Class,
     Op::Inherit,
     Op::Method,
+    Op::SmallInt,
+    Op::Yield,
+    Op::SetGlobalPop,
+    Op::SetLocalPop,
+    Op::Stringify,
+    Op::Dup,
+    Op::GreaterEqual,
+    Op::LessEqual,
+    Op::GetThis,
+    Op::Modulo,
+    Op::InvokeThis,
+    Op::AddNum,
+    Op::AddStr,
+    Op::BuildList,
+    Op::Index,
+    Op::SetIndex,
+    Op::MethodDynamic,
+    Op::JumpLong,
+    Op::JumpIfFalseLong,
+    Op::LoopLong,
+    Op::DeepEqual,
+    Op::StaticMethod,
+    Op::PopN,
+    Op::TailCall,
+    Op::Undefined,
+    Op::Power,
 ];
 
 impl TryFrom<u8> for Op {
     type Error = String;
 
     fn try_from(op: u8) -> Result<Self, Self::Error> {
-        if op > Op::Method as u8 {
+        if op > Op::Power as u8 {
             return Err(format!("{op} is not a valid opcode"));
         }
         Ok(OP_CODES[op as usize])
     }
 }
 
+impl Op {
+    // closures are followed by a pair of bytes for each upvalue as well
+    pub fn operand_bytes(self) -> usize {
+        match self {
+            Op::Invoke | Op::InvokeThis => 4,
+            Op::Jump
+            | Op::JumpIfFalse
+            | Op::JumpIfFalseLong
+            | Op::JumpLong
+            | Op::Loop
+            | Op::LoopLong
+            | Op::SuperInvoke => 2,
+            Op::BuildList
+            | Op::Call
+            | Op::Class
+            | Op::Closure
+            | Op::Constant
+            | Op::DefineGlobal
+            | Op::GetGlobal
+            | Op::GetLocal
+            | Op::GetProperty
+            | Op::GetSuper
+            | Op::GetUpvalue
+            | Op::Method
+            | Op::PopN
+            | Op::SetGlobal
+            | Op::SetGlobalPop
+            | Op::SetLocal
+            | Op::SetLocalPop
+            | Op::SetProperty
+            | Op::SetUpvalue
+            | Op::SmallInt
+            | Op::StaticMethod
+            | Op::TailCall => 1,
+            Op::Add
+            | Op::AddNum
+            | Op::AddStr
+            | Op::CloseUpvalue
+            | Op::DeepEqual
+            | Op::Divide
+            | Op::Dup
+            | Op::Equal
+            | Op::False
+            | Op::GetThis
+            | Op::Greater
+            | Op::GreaterEqual
+            | Op::Index
+            | Op::Inherit
+            | Op::Less
+            | Op::LessEqual
+            | Op::MethodDynamic
+            | Op::Modulo
+            | Op::Multiply
+            | Op::Negative
+            | Op::Nil
+            | Op::Not
+            | Op::Pop
+            | Op::Power
+            | Op::Print
+            | Op::Return
+            | Op::SetIndex
+            | Op::Stringify
+            | Op::Subtract
+            | Op::True
+            | Op::Undefined
+            | Op::Yield => 0,
+        }
+    }
+
+    // none if the effect depends on the arity operand
+    pub fn stack_effect(self) -> Option<i32> {
+        match self {
+            Op::Class
+            | Op::Closure
+            | Op::Constant
+            | Op::Dup
+            | Op::False
+            | Op::GetGlobal
+            | Op::GetLocal
+            | Op::GetThis
+            | Op::GetUpvalue
+            | Op::Nil
+            | Op::SmallInt
+            | Op::True
+            | Op::Undefined => Some(1),
+            Op::Add
+            | Op::AddNum
+            | Op::AddStr
+            | Op::CloseUpvalue
+            | Op::DeepEqual
+            | Op::DefineGlobal
+            | Op::Divide
+            | Op::Equal
+            | Op::GetSuper
+            | Op::Greater
+            | Op::GreaterEqual
+            | Op::Index
+            | Op::Inherit
+            | Op::Less
+            | Op::LessEqual
+            | Op::Method
+            | Op::Modulo
+            | Op::Multiply
+            | Op::Pop
+            | Op::Power
+            | Op::Print
+            | Op::Return
+            | Op::SetGlobalPop
+            | Op::SetLocalPop
+            | Op::SetProperty
+            | Op::StaticMethod
+            | Op::Subtract
+            | Op::Yield => Some(-1),
+            Op::MethodDynamic | Op::SetIndex => Some(-2),
+            Op::BuildList
+            | Op::Call
+            | Op::Invoke
+            | Op::InvokeThis
+            | Op::PopN
+            | Op::SuperInvoke
+            | Op::TailCall => None,
+            Op::GetProperty
+            | Op::Jump
+            | Op::JumpIfFalse
+            | Op::JumpIfFalseLong
+            | Op::JumpLong
+            | Op::Loop
+            | Op::LoopLong
+            | Op::Negative
+            | Op::Not
+            | Op::SetGlobal
+            | Op::SetLocal
+            | Op::SetUpvalue
+            | Op::Stringify => Some(0),
+        }
+    }
+}
+
 // heap allocated
 pub struct Chunk {
     code: Vec<u8>,
     pub lines: Vec<u16>,
+    pub columns: Vec<u16>,
     pub constants: Vec<Value>,
+    // constant operands are relative to the segment of the instruction:
+    // (code offset, constant offset) pairs, empty while a byte suffices
+    segments: Vec<(usize, usize)>,
+    // the last class and method seen by each invoke instruction
+    caches: Vec<Option<(GC<Class>, GC<Closure>)>>,
+    // distances of jumps that do not fit their short operand
+    long_jumps: Vec<u32>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
@@ -106,7 +313,11 @@ impl Chunk {
         Self {
             code: Vec::new(),
             lines: Vec::new(),
+            columns: Vec::new(),
             constants: Vec::new(),
+            segments: Vec::new(),
+            caches: Vec::new(),
+            long_jumps: Vec::new(),
         }
     }
     pub fn write(&mut self, bytes: &[u8], line: u16) {
@@ -115,39 +326,101 @@ impl Chunk {
             self.lines.push(line);
         }
     }
+    // give the column to the code written since the last call
+    pub fn mark_column(&mut self, column: u16) {
+        self.columns.resize(self.code.len(), column);
+    }
+    // line and column of the instruction
+    pub fn location(&self, ip: usize) -> (u16, u16) {
+        let column = self.columns.get(ip).copied().unwrap_or(0);
+        (self.lines[ip], column)
+    }
     pub fn patch_jump(&mut self, offset: usize) -> Result<(), String> {
         assert!({
             let op = self.code[offset - 1];
             op == (Op::Jump as u8) || op == (Op::JumpIfFalse as u8) || op == (Op::Loop as u8)
         });
         let jump = self.code.len() - offset;
-        if jump > u16::MAX as usize {
-            return err!("Jump too large");
-        }
         if jump == 0 {
             return err!("Not a jump");
         }
-        self.code[offset] = (jump >> 8) as u8;
-        self.code[offset + 1] = jump as u8;
+        self.set_jump(offset - 1, jump)
+    }
+    // set the distance of the jump or loop at the offset, counted from the
+    // operand. distances beyond a short go in the long jump table, which turns
+    // the instruction into its long variant without moving any code
+    pub fn set_jump(&mut self, offset: usize, distance: usize) -> Result<(), String> {
+        let op = Op::try_from(self.code[offset])?;
+        let long_op = match op {
+            Op::Jump | Op::JumpLong => Op::JumpLong,
+            Op::JumpIfFalse | Op::JumpIfFalseLong => Op::JumpIfFalseLong,
+            Op::Loop | Op::LoopLong => Op::LoopLong,
+            _ => return err!("Not a jump"),
+        };
+        if distance > u32::MAX as usize {
+            return err!("Jump too large");
+        }
+        let operand = if op == long_op {
+            let index = self.read_short(offset + 1);
+            self.long_jumps[index as usize] = distance as u32;
+            index
+        } else if distance > u16::MAX as usize {
+            if self.long_jumps.len() > u16::MAX as usize {
+                return err!("Too many long jumps");
+            }
+            self.long_jumps.push(distance as u32);
+            self.code[offset] = long_op as u8;
+            (self.long_jumps.len() - 1) as u16
+        } else {
+            distance as u16
+        };
+        self.code[offset + 1] = (operand >> 8) as u8;
+        self.code[offset + 2] = operand as u8;
         Ok(())
     }
+    pub fn replace_op(&mut self, offset: usize, op: Op) {
+        self.code[offset] = op as u8;
+    }
     pub fn count(&self) -> usize {
         self.code.len()
     }
+    // drop the code from count onwards, leaving the constants in place
+    pub fn truncate(&mut self, count: usize) {
+        self.code.truncate(count);
+        self.lines.truncate(count);
+        self.columns.truncate(count);
+        while matches!(self.segments.last(), Some(&(offset, _)) if offset >= count) {
+            self.segments.pop();
+        }
+    }
+    fn constant_base(&self, index: usize) -> usize {
+        match self
+            .segments
+            .partition_point(|&(offset, _)| offset <= index)
+        {
+            0 => 0,
+            i => self.segments[i - 1].1,
+        }
+    }
+
+    // the index is only valid for an instruction written right after this call
     pub fn add_constant(&mut self, value: Value) -> Result<u8, String> {
-        let mut i = 0;
+        let base = self.constant_base(self.code.len());
+        let mut i = base;
         while i < self.constants.len() {
             if self.constants[i] == value {
-                return Ok(i as u8);
+                return Ok((i - base) as u8);
             } else {
                 i += 1;
             }
         }
-        if i > u8::MAX as usize {
-            err!("Too many constants in function, {} won't fit.", value)
+        if i - base > u8::MAX as usize {
+            self.segments.push((self.code.len(), i));
+            self.constants.push(value);
+            Ok(0)
         } else {
             self.constants.push(value);
-            Ok(i as u8)
+            Ok((i - base) as u8)
         }
     }
 
@@ -165,6 +438,24 @@ impl Chunk {
         self.lines.push(line);
         self.lines.push(line);
     }
+    pub fn write_cached_invoke(
+        &mut self,
+        op: Op,
+        constant: u8,
+        arity: u8,
+        line: u16,
+    ) -> Result<(), String> {
+        let slot = self.caches.len();
+        if slot > u16::MAX as usize {
+            return err!("Too many method calls in one function.");
+        }
+        self.caches.push(None);
+        self.write(
+            &[op as u8, constant, arity, (slot >> 8) as u8, slot as u8],
+            line,
+        );
+        Ok(())
+    }
     pub fn write_short_op(&mut self, op: Op, short: u16, line: u16) {
         self.code.push(op as u8);
         self.code.push((short >> 8) as u8);
@@ -174,6 +465,133 @@ impl Chunk {
         self.lines.push(line);
     }
 
+    // the number of bytes taken by the instruction at the offset
+    pub fn instruction_length(&self, offset: usize) -> usize {
+        match Op::try_from(self.code[offset]) {
+            Ok(Op::Closure) => {
+                let function = GC::<Function>::from(self.read_constant(offset + 1));
+                2 + 2 * function.upvalue_count as usize
+            }
+            Ok(op) => 1 + op.operand_bytes(),
+            Err(_) => 1,
+        }
+    }
+
+    pub fn jump_distance(&self, offset: usize) -> usize {
+        let operand = self.read_short(offset + 1);
+        match Op::try_from(self.code[offset]) {
+            Ok(Op::JumpLong | Op::JumpIfFalseLong | Op::LoopLong) => {
+                self.long_jumps[operand as usize] as usize
+            }
+            _ => operand as usize,
+        }
+    }
+
+    pub fn jump_target(&self, offset: usize) -> usize {
+        offset + 1 + self.jump_distance(offset)
+    }
+
+    pub fn loop_target(&self, offset: usize) -> usize {
+        offset + 1 - self.jump_distance(offset)
+    }
+
+    // let forward jumps that land on a jump go to the final target instead
+    pub fn thread_jumps(&mut self) -> Result<(), String> {
+        let mut offset = 0;
+        while offset < self.code.len() {
+            if let Ok(Op::Jump | Op::JumpIfFalse | Op::JumpLong | Op::JumpIfFalseLong) =
+                Op::try_from(self.code[offset])
+            {
+                let mut target = self.jump_target(offset);
+                while let Some(Ok(Op::Jump | Op::JumpLong)) =
+                    self.code.get(target).map(|&op| Op::try_from(op))
+                {
+                    target = self.jump_target(target);
+                }
+                // a threaded jump can become long, and the table can be full
+                self.set_jump(offset, target - offset - 1)?;
+            }
+            offset += self.instruction_length(offset);
+        }
+        Ok(())
+    }
+
+    // net change in stack height caused by the instruction at the offset
+    fn stack_effect(&self, op: Op, offset: usize) -> isize {
+        match op.stack_effect() {
+            Some(effect) => effect as isize,
+            None => match op {
+                Op::Call | Op::TailCall => -(self.code[offset + 1] as isize),
+                Op::BuildList => 1 - (self.code[offset + 1] as isize),
+                Op::Invoke => -(self.code[offset + 2] as isize),
+                Op::InvokeThis => 1 - (self.code[offset + 2] as isize),
+                Op::PopN => -(self.code[offset + 1] as isize),
+                _ => -(self.code[offset + 2] as isize) - 1,
+            },
+        }
+    }
+
+    // check that all paths agree on the stack height, stay above the base and
+    // return a value
+    pub fn verify(&self, base: usize) -> Result<(), String> {
+        let mut heights: Vec<Option<usize>> = vec![None; self.code.len()];
+        let mut pending = vec![(0, base)];
+        while let Some((offset, height)) = pending.pop() {
+            if offset >= self.code.len() {
+                return err!("Missing return at {}.", offset);
+            }
+            match heights[offset] {
+                Some(h) if h == height => continue,
+                Some(h) => return err!("Stack heights {} and {} meet at {}.", h, height, offset),
+                None => heights[offset] = Some(height),
+            }
+            let op = Op::try_from(self.code[offset])?;
+            let after = height as isize + self.stack_effect(op, offset);
+            if after < base as isize {
+                return err!("Stack underflow at {}.", offset);
+            }
+            let after = after as usize;
+            let next = offset + self.instruction_length(offset);
+            match op {
+                Op::Jump | Op::JumpLong => pending.push((self.jump_target(offset), after)),
+                Op::JumpIfFalse | Op::JumpIfFalseLong => {
+                    pending.push((self.jump_target(offset), after));
+                    pending.push((next, after));
+                }
+                Op::Loop | Op::LoopLong => pending.push((self.loop_target(offset), after)),
+                Op::Return => (),
+                _ => pending.push((next, after)),
+            }
+        }
+        Ok(())
+    }
+
+    // (line, run length) pairs covering the code
+    pub fn line_table(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        let mut index = 0;
+        std::iter::from_fn(move || {
+            let line = *self.lines.get(index)?;
+            let start = index;
+            while index < self.lines.len()
+                && self.lines[index] == line
+                && index - start < u16::MAX as usize
+            {
+                index += 1;
+            }
+            Some((line, (index - start) as u16))
+        })
+    }
+
+    pub fn instruction_offsets(&self) -> Vec<usize> {
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        while offset < self.code.len() {
+            offsets.push(offset);
+            offset += self.instruction_length(offset);
+        }
+        offsets
+    }
+
     pub fn read_byte(&self, index: usize) -> u8 {
         self.code[index]
     }
@@ -181,10 +599,64 @@ impl Chunk {
         (self.read_byte(index) as u16) << 8 | (self.read_byte(index + 1) as u16)
     }
     pub fn read_constant(&self, index: usize) -> Value {
-        self.constants[self.read_byte(index) as usize]
+        self.constants[self.constant_base(index) + self.read_byte(index) as usize]
+    }
+    pub fn read_cache(&self, slot: u16) -> Option<(GC<Class>, GC<Closure>)> {
+        self.caches[slot as usize]
+    }
+    pub fn write_cache(&mut self, slot: u16, class: GC<Class>, method: GC<Closure>) {
+        self.caches[slot as usize] = Some((class, method));
+    }
+    // cached classes stay alive, so their handles cannot be reused
+    pub fn trace_caches(&self, collector: &mut Vec<Handle>) {
+        for &(class, method) in self.caches.iter().flatten() {
+            collector.push(Handle::from(class));
+            collector.push(Handle::from(method));
+        }
     }
     // count adjustment after compiling
     pub fn byte_increment(&self) -> usize {
-        self.code.capacity() + 2 * self.lines.capacity() + 2 * self.constants.capacity()
+        self.code.capacity()
+            + 2 * self.columns.capacity()
+            + 2 * self.lines.capacity()
+            + 2 * self.constants.capacity()
+            + 16 * self.segments.capacity()
+            + 24 * self.caches.capacity()
+            + 4 * self.long_jumps.capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn op_metadata() {
+        assert_eq!(Op::Add.stack_effect(), Some(-1));
+        assert_eq!(Op::Call.stack_effect(), None);
+        assert_eq!(Op::Constant.operand_bytes(), 1);
+        assert_eq!(Op::Invoke.operand_bytes(), 4);
+        assert_eq!(Op::Return.operand_bytes(), 0);
+    }
+
+    #[test]
+    fn verify_stack_heights() {
+        let mut chunk = Chunk::new();
+        chunk.write(&[Op::Nil as u8, Op::Return as u8], 1);
+        assert_eq!(chunk.verify(1), Ok(()));
+
+        let mut chunk = Chunk::new();
+        chunk.write(&[Op::Nil as u8, Op::Pop as u8, Op::Pop as u8], 1);
+        chunk.write(&[Op::Nil as u8, Op::Return as u8], 1);
+        assert_eq!(chunk.verify(1), Err(String::from("Stack underflow at 2.")));
+
+        // the branch that is taken skips a push
+        let mut chunk = Chunk::new();
+        chunk.write(&[Op::True as u8, Op::JumpIfFalse as u8, 0, 3], 1);
+        chunk.write(&[Op::Nil as u8, Op::Nil as u8, Op::Return as u8], 1);
+        assert_eq!(
+            chunk.verify(1),
+            Err(String::from("Stack heights 3 and 2 meet at 5."))
+        );
     }
 }
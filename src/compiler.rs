@@ -1,37 +1,45 @@
 use std::{
     ops::{Deref, DerefMut},
     ptr,
-    time::Instant,
 };
 
 use crate::{
     chunk::{Chunk, Op},
+    loxtr::Loxtr,
     memory::{Heap, Traceable, GC},
-    object::{Function, Value},
+    object::{Closure, Function, Value},
     scanner::{Scanner, Token, TokenType},
 };
 
 #[derive(PartialEq, PartialOrd)]
 pub enum Prec {
     None,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
+    Assignment,  // = ..
+    Conditional, // ?:
+    Or,          // or
+    And,         // and
+    Equality,    // == !=
+    Comparison,  // < > <= >=
+    Term,        // + -
+    Factor,      // * /
+    Unary,       // ! -
+    Power,       // **
+    Call,        // . ()
 }
 
 impl TokenType {
     fn precedence(&self) -> Prec {
         match self {
-            TokenType::LeftParen | TokenType::Dot => Prec::Call,
+            TokenType::LeftParen | TokenType::LeftBracket | TokenType::Dot => Prec::Call,
+            // binds loosest, so cascades can follow the assigned values
+            TokenType::DotDot => Prec::Assignment,
+            TokenType::Question => Prec::Conditional,
             TokenType::Minus | TokenType::Plus => Prec::Term,
-            TokenType::Slash | TokenType::Star => Prec::Factor,
-            TokenType::BangEqual | TokenType::EqualEqual => Prec::Equality,
+            TokenType::Percent | TokenType::Slash | TokenType::Star => Prec::Factor,
+            TokenType::StarStar => Prec::Power,
+            TokenType::BangEqual | TokenType::EqualEqual | TokenType::EqualEqualEqual => {
+                Prec::Equality
+            }
             TokenType::Greater
             | TokenType::GreaterEqual
             | TokenType::Less
@@ -47,6 +55,9 @@ struct Local<'src> {
     name: Token<'src>,
     depth: Option<u16>,
     is_captured: bool,
+    is_used: bool,
+    #[cfg(feature = "debug_info")]
+    start: usize,
 }
 
 impl<'src> Local<'src> {
@@ -55,6 +66,9 @@ impl<'src> Local<'src> {
             name,
             depth: None,
             is_captured: false,
+            is_used: false,
+            #[cfg(feature = "debug_info")]
+            start: 0,
         }
     }
 }
@@ -68,9 +82,11 @@ struct Upvalue {
 #[derive(Eq, PartialEq)]
 enum FunctionType {
     Function,
+    Getter,
     Initializer,
     Method,
     Script,
+    Static,
 }
 
 // the nuclear option, then?
@@ -123,6 +139,14 @@ struct Compiler<'src, 'hp> {
     locals: Vec<Local<'src>>,
     enclosing: StackRef<Compiler<'src, 'hp>>,
     source: StackRef<Source<'src, 'hp>>,
+    // code offsets right after the last assignment, the last jump target,
+    // the last expression statement, the last return statement and the last
+    // call
+    last_assignment: Option<usize>,
+    last_jump_target: Option<usize>,
+    last_expression: Option<usize>,
+    last_return: Option<usize>,
+    last_call: Option<usize>,
 }
 
 impl<'src, 'hp> Compiler<'src, 'hp> {
@@ -132,7 +156,7 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         source: StackRef<Source<'src, 'hp>>,
     ) -> Self {
         let mut first_local = Local::new(Token::synthetic(
-            if function_type == FunctionType::Function {
+            if matches!(function_type, FunctionType::Function | FunctionType::Static) {
                 ""
             } else {
                 "this"
@@ -147,6 +171,11 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
             locals: vec![first_local],
             enclosing: StackRef::null(),
             source,
+            last_assignment: None,
+            last_jump_target: None,
+            last_expression: None,
+            last_return: None,
+            last_call: None,
         }
     }
 
@@ -186,9 +215,40 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         }
         let i = self.locals.len() - 1;
         self.locals[i].depth = Some(self.scope_depth);
+        #[cfg(feature = "debug_info")]
+        {
+            self.locals[i].start = self.current_chunk().count();
+        }
         true
     }
 
+    fn pop_local(&mut self) {
+        if let Some(local) = self.locals.last() {
+            // synthetic locals have no line, names starting with '_' opt out
+            if self.source.strict
+                && !local.is_used
+                && local.depth.is_some()
+                && local.name.line > 0
+                && !local.name.lexeme.starts_with('_')
+            {
+                let name = local.name;
+                self.source
+                    .warn_at(name, &format!("Unused variable '{}'.", name.lexeme));
+            }
+        }
+        #[cfg(feature = "debug_info")]
+        if let Some(local) = self.locals.last() {
+            // the first slot holds the callee
+            if self.locals.len() > 1 && local.depth.is_some() {
+                let name = Box::from(local.name.lexeme);
+                let slot = (self.locals.len() - 1) as u8;
+                let range = local.start..self.current_chunk().count();
+                self.function.locals.push((name, slot, range));
+            }
+        }
+        self.locals.pop();
+    }
+
     fn add_upvalue(&mut self, index: u8, is_local: bool) -> Result<u8, String> {
         let count = self.upvalues.len();
         for i in 0..count {
@@ -211,6 +271,7 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
 
         if let Some(index) = self.enclosing.resolve_local(name)? {
             self.enclosing.locals[index as usize].is_captured = true;
+            self.enclosing.locals[index as usize].is_used = true;
             return Ok(Some(self.add_upvalue(index, true)?));
         }
 
@@ -256,35 +317,41 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         self.emit_op(Op::Return);
     }
 
+    // the previous token locates everything emitted since the last call
+    fn mark_column(&mut self) {
+        let column = self.source.previous_token.column;
+        self.current_chunk().mark_column(column);
+    }
+
     fn emit_byte_op(&mut self, op: Op, byte: u8) {
         let line = self.source.previous_token.line;
         self.current_chunk().write_byte_op(op, byte, line);
+        self.mark_column();
     }
 
     fn emit_short_op(&mut self, op: Op, short: u16) {
         let line = self.source.previous_token.line;
         self.current_chunk().write_short_op(op, short, line);
+        self.mark_column();
     }
 
     fn emit_invoke_op(&mut self, op: Op, constant: u8, arity: u8) {
         let line = self.source.previous_token.line;
         self.current_chunk()
             .write_invoke_op(op, constant, arity, line);
+        self.mark_column();
     }
 
     fn emit_op(&mut self, op: Op) {
         let line = self.source.previous_token.line;
         self.current_chunk().write(&[op as u8], line);
+        self.mark_column();
     }
 
     fn emit_loop(&mut self, start: usize) -> Result<(), String> {
-        let offset = self.current_chunk().count() - start + 1;
-        if offset > u16::MAX as usize {
-            err!("loop size to large")
-        } else {
-            self.emit_short_op(Op::Loop, offset as u16);
-            Ok(())
-        }
+        let offset = self.current_chunk().count();
+        self.emit_short_op(Op::Loop, 0);
+        self.current_chunk().set_jump(offset, offset + 1 - start)
     }
 
     fn emit_jump(&mut self, instruction: Op) -> usize {
@@ -305,23 +372,36 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
     fn end_scope(&mut self) {
         self.scope_depth -= 1;
         let scope_depth = self.scope_depth;
+        let mut pops = 0;
         loop {
             let is_captured = match self.locals.last() {
-                None => return,
+                None => break,
                 Some(local) => {
                     if local.depth.is_none() || local.depth.unwrap() <= scope_depth {
-                        return;
+                        break;
                     } else {
                         local.is_captured
                     }
                 }
             };
-            self.emit_op(if is_captured {
-                Op::CloseUpvalue
+            // runs of uncaptured locals are popped together
+            if is_captured {
+                self.emit_pops(pops);
+                pops = 0;
+                self.emit_op(Op::CloseUpvalue);
             } else {
-                Op::Pop
-            });
-            self.locals.pop();
+                pops += 1;
+            }
+            self.pop_local();
+        }
+        self.emit_pops(pops);
+    }
+
+    fn emit_pops(&mut self, count: u8) {
+        match count {
+            0 => (),
+            1 => self.emit_op(Op::Pop),
+            _ => self.emit_byte_op(Op::PopN, count),
         }
     }
 
@@ -351,41 +431,133 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         self.emit_op(Op::Pop);
         self.parse_precedence(Prec::And)?;
 
-        self.current_chunk().patch_jump(end_jump)
+        self.patch_jump(end_jump)
     }
 
-    fn binary(&mut self) -> Result<(), String> {
-        match self.source.previous_token_type() {
-            TokenType::BangEqual => {
-                self.parse_precedence(Prec::Equality)?;
-                self.emit_op(Op::Equal);
-                self.emit_op(Op::Not);
+    // the value of the code from start to end, if it is a single literal
+    fn literal(&mut self, start: usize, end: usize) -> Option<Value> {
+        let chunk = self.current_chunk();
+        if start >= end {
+            return None;
+        }
+        match (Op::try_from(chunk.read_byte(start)).ok()?, end - start) {
+            (Op::Constant, 2) => match chunk.read_constant(start + 1) {
+                Value::Object(_) => None,
+                value => Some(value),
+            },
+            (Op::SmallInt, 2) => Some(Value::from(chunk.read_byte(start + 1) as f64)),
+            (Op::False, 1) => Some(Value::False),
+            (Op::Nil, 1) => Some(Value::Nil),
+            (Op::True, 1) => Some(Value::True),
+            _ => None,
+        }
+    }
+
+    // the addition for an operand computed by the code from start to end, if
+    // the type of the operand shows without following jumps
+    fn typed_add(&mut self, start: usize, end: usize) -> Option<Op> {
+        if let Some(value) = self.literal(start, end) {
+            return matches!(value, Value::Number(_)).then_some(Op::AddNum);
+        }
+        let chunk = self.current_chunk();
+        let mut last = None;
+        let mut offset = start;
+        while offset < end {
+            let op = Op::try_from(chunk.read_byte(offset)).ok()?;
+            if matches!(
+                op,
+                Op::Jump
+                    | Op::JumpIfFalse
+                    | Op::JumpIfFalseLong
+                    | Op::JumpLong
+                    | Op::Loop
+                    | Op::LoopLong
+            ) {
+                return None;
             }
-            TokenType::EqualEqual => {
-                self.parse_precedence(Prec::Equality)?;
-                self.emit_op(Op::Equal)
+            last = Some((offset, op));
+            offset += chunk.instruction_length(offset);
+        }
+        match last? {
+            (offset, Op::Constant) if offset == start => {
+                Loxtr::nullable(chunk.read_constant(offset + 1)).map(|_| Op::AddStr)
             }
-            TokenType::Greater => {
-                self.parse_precedence(Prec::Equality)?;
-                self.emit_op(Op::Greater)
+            (_, Op::AddStr | Op::Stringify) => Some(Op::AddStr),
+            (
+                _,
+                Op::AddNum | Op::Subtract | Op::Multiply | Op::Divide | Op::Modulo | Op::Negative,
+            ) => Some(Op::AddNum),
+            _ => None,
+        }
+    }
+
+    fn fold_comparison(
+        &mut self,
+        token_type: TokenType,
+        left_start: usize,
+        right_start: usize,
+    ) -> bool {
+        let end = self.current_chunk().count();
+        let (Some(a), Some(b)) = (
+            self.literal(left_start, right_start),
+            self.literal(right_start, end),
+        ) else {
+            return false;
+        };
+        // comparisons involving NaN are false
+        let result = match (token_type, a, b) {
+            (TokenType::EqualEqual, a, b) => a == b,
+            (TokenType::BangEqual, a, b) => a != b,
+            (TokenType::Greater, Value::Number(a), Value::Number(b)) => a > b,
+            (TokenType::GreaterEqual, Value::Number(a), Value::Number(b)) => a >= b,
+            (TokenType::Less, Value::Number(a), Value::Number(b)) => a < b,
+            (TokenType::LessEqual, Value::Number(a), Value::Number(b)) => a <= b,
+            _ => return false,
+        };
+        self.current_chunk().truncate(left_start);
+        self.emit_op(if result { Op::True } else { Op::False });
+        true
+    }
+
+    fn binary(&mut self, left_start: usize) -> Result<(), String> {
+        let token_type = self.source.previous_token_type();
+        let right_start = self.current_chunk().count();
+        if matches!(
+            token_type,
+            TokenType::BangEqual
+                | TokenType::EqualEqual
+                | TokenType::Greater
+                | TokenType::GreaterEqual
+                | TokenType::Less
+                | TokenType::LessEqual
+        ) {
+            self.parse_precedence(Prec::Equality)?;
+            if self.fold_comparison(token_type, left_start, right_start) {
+                return Ok(());
             }
-            TokenType::GreaterEqual => {
-                self.parse_precedence(Prec::Equality)?;
-                self.emit_op(Op::Less);
+        }
+        match token_type {
+            TokenType::BangEqual => {
+                self.emit_op(Op::Equal);
                 self.emit_op(Op::Not);
             }
-            TokenType::Less => {
+            TokenType::EqualEqual => self.emit_op(Op::Equal),
+            TokenType::EqualEqualEqual => {
                 self.parse_precedence(Prec::Equality)?;
-                self.emit_op(Op::Less)
-            }
-            TokenType::LessEqual => {
-                self.parse_precedence(Prec::Equality)?;
-                self.emit_op(Op::Greater);
-                self.emit_op(Op::Not);
+                self.emit_op(Op::DeepEqual)
             }
+            TokenType::Greater => self.emit_op(Op::Greater),
+            TokenType::GreaterEqual => self.emit_op(Op::GreaterEqual),
+            TokenType::Less => self.emit_op(Op::Less),
+            TokenType::LessEqual => self.emit_op(Op::LessEqual),
             TokenType::Plus => {
                 self.parse_precedence(Prec::Factor)?;
-                self.emit_op(Op::Add)
+                let end = self.current_chunk().count();
+                let op = self
+                    .typed_add(left_start, right_start)
+                    .or_else(|| self.typed_add(right_start, end))
+                    .unwrap_or(Op::Add);
+                self.emit_op(op)
             }
             TokenType::Minus => {
                 self.parse_precedence(Prec::Factor)?;
@@ -399,6 +571,15 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
                 self.parse_precedence(Prec::Unary)?;
                 self.emit_op(Op::Divide)
             }
+            TokenType::Percent => {
+                self.parse_precedence(Prec::Unary)?;
+                self.emit_op(Op::Modulo)
+            }
+            TokenType::StarStar => {
+                // the same precedence on the right makes it right associative
+                self.parse_precedence(Prec::Power)?;
+                self.emit_op(Op::Power)
+            }
             _ => (), // Unreachable.
         }
         Ok(())
@@ -407,27 +588,116 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
     fn call(&mut self) -> Result<(), String> {
         let arity = self.argument_list()?;
         self.emit_byte_op(Op::Call, arity);
+        self.last_call = Some(self.current_chunk().count());
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<(), String> {
+        let mut count: u8 = 0;
+        if !self.source.check(TokenType::RightBracket) {
+            loop {
+                self.expression()?;
+                if count == u8::MAX {
+                    return err!("Can't have more than 255 elements in a list literal.");
+                }
+                count += 1;
+                if !self.source.match_type(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.source
+            .consume(TokenType::RightBracket, "Expect ']' after list elements.")?;
+        self.emit_byte_op(Op::BuildList, count);
+        Ok(())
+    }
+
+    fn subscript(&mut self, can_assign: bool) -> Result<(), String> {
+        self.expression()?;
+        self.source
+            .consume(TokenType::RightBracket, "Expect ']' after index.")?;
+        if can_assign && self.source.match_type(TokenType::Equal) {
+            self.expression()?;
+            self.emit_op(Op::SetIndex);
+        } else {
+            self.emit_op(Op::Index);
+        }
         Ok(())
     }
 
     fn dot(&mut self, can_assign: bool) -> Result<(), String> {
-        let index = self.identifier_constant("Expect property name after '.'.")?;
+        let name = self.identifier("Expect property name after '.'.")?;
+        self.property(name, can_assign)
+    }
+
+    fn property(&mut self, name: &'src str, can_assign: bool) -> Result<(), String> {
         if can_assign && self.source.match_type(TokenType::Equal) {
             self.expression()?;
+            let index = self.intern(name)?;
             self.emit_byte_op(Op::SetProperty, index)
         } else if self.source.match_type(TokenType::LeftParen) {
             let arity = self.argument_list()?;
-            self.emit_invoke_op(Op::Invoke, index, arity);
+            let index = self.intern(name)?;
+            let line = self.source.previous_token.line;
+            self.current_chunk()
+                .write_cached_invoke(Op::Invoke, index, arity, line)?;
+            self.mark_column();
         } else {
+            let index = self.intern(name)?;
             self.emit_byte_op(Op::GetProperty, index);
         };
         Ok(())
     }
 
+    // set a property and leave the receiver on the stack
+    fn cascade(&mut self) -> Result<(), String> {
+        let name = self.identifier("Expect property name after '..'.")?;
+        self.source
+            .consume(TokenType::Equal, "Expect '=' after cascaded property.")?;
+        self.emit_op(Op::Dup);
+        self.parse_precedence(Prec::Or)?;
+        let index = self.intern(name)?;
+        self.emit_byte_op(Op::SetProperty, index);
+        self.emit_op(Op::Pop);
+        Ok(())
+    }
+
     fn number(&mut self) -> Result<(), String> {
-        match self.source.lexeme().parse::<f64>() {
-            Ok(number) => self.emit_constant(Value::from(number)),
-            Err(err) => Err(err.to_string()),
+        let lexeme = self.source.lexeme();
+        let hex = lexeme.strip_prefix("0x").or(lexeme.strip_prefix("0X"));
+        let parsed = match hex {
+            // any number of digits, rounded like a decimal literal would be
+            Some("") => None,
+            Some(digits) => digits.chars().try_fold(0.0, |number: f64, ch| {
+                Some(number * 16.0 + ch.to_digit(16)? as f64)
+            }),
+            None => lexeme.parse::<f64>().ok(),
+        };
+        match parsed {
+            Some(number) => {
+                let exact = match hex {
+                    Some(digits) => number.is_finite() && significant_bits(digits) <= 53,
+                    None if lexeme.bytes().all(|b| b.is_ascii_digit()) => {
+                        let digits = lexeme.trim_start_matches('0');
+                        format!("{:.0}", number) == if digits.is_empty() { "0" } else { digits }
+                    }
+                    None => true,
+                };
+                if self.source.strict && !exact {
+                    self.source.warn(&format!(
+                        "Integer literal cannot be represented exactly; it becomes {:.0}.",
+                        number
+                    ));
+                }
+                // small integers need no constant
+                if number.fract() == 0.0 && number.is_sign_positive() && number <= 255.0 {
+                    self.emit_byte_op(Op::SmallInt, number as u8);
+                    Ok(())
+                } else {
+                    self.emit_constant(Value::from(number))
+                }
+            }
+            None => err!("Malformed number '{}'.", lexeme),
         }
     }
 
@@ -435,25 +705,71 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         let else_jump = self.emit_jump(Op::JumpIfFalse);
         let end_jump = self.emit_jump(Op::Jump);
 
-        self.current_chunk().patch_jump(else_jump)?;
+        self.patch_jump(else_jump)?;
         self.emit_op(Op::Pop);
 
         self.parse_precedence(Prec::Or)?;
 
-        self.current_chunk().patch_jump(end_jump)?;
+        self.patch_jump(end_jump)?;
         Ok(())
     }
 
+    fn conditional(&mut self) -> Result<(), String> {
+        let else_jump = self.emit_jump(Op::JumpIfFalse);
+        self.emit_op(Op::Pop);
+        self.expression()?;
+        self.source
+            .consume(TokenType::Colon, "Expect ':' after then branch.")?;
+        let end_jump = self.emit_jump(Op::Jump);
+
+        self.patch_jump(else_jump)?;
+        self.emit_op(Op::Pop);
+        // right associative
+        self.parse_precedence(Prec::Conditional)?;
+
+        self.patch_jump(end_jump)
+    }
+
     fn string(&mut self) -> Result<(), String> {
-        let lexeme = self.source.lexeme();
-        let value = Value::from(self.source.heap.intern_copy(&lexeme[1..lexeme.len() - 1]));
+        let text = unescape(self.source.previous_token)?;
+        let value = Value::from(self.source.heap.intern(text));
         self.emit_constant(value)
     }
 
+    // concatenate the parts of an interpolated string, skipping empty ones
+    fn interpolation(&mut self) -> Result<(), String> {
+        let mut first = true;
+        loop {
+            let part = unescape(self.source.previous_token)?;
+            if !part.is_empty() {
+                let value = Value::from(self.source.heap.intern(part));
+                self.emit_constant(value)?;
+                if !first {
+                    self.emit_op(Op::AddStr);
+                }
+                first = false;
+            }
+            if self.source.previous_token_type() == TokenType::String {
+                return Ok(());
+            }
+            self.expression()?;
+            self.emit_op(Op::Stringify);
+            if !first {
+                self.emit_op(Op::AddStr);
+            }
+            first = false;
+            if !self.source.match_type(TokenType::Interpolation) {
+                self.source
+                    .consume(TokenType::String, "Expect '}' after interpolation.")?;
+            }
+        }
+    }
+
     // admit code for variable access
     fn variable(&mut self, name: &'src str, can_assign: bool) -> Result<(), String> {
         let (arg, get, set) = {
             if let Some(arg) = self.resolve_local(name)? {
+                self.locals[arg as usize].is_used = true;
                 (arg, Op::GetLocal, Op::SetLocal)
             } else if let Some(arg) = self.resolve_upvalue(name)? {
                 (arg, Op::GetUpvalue, Op::SetUpvalue)
@@ -466,13 +782,33 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
 
         if can_assign && self.source.match_type(TokenType::Equal) {
             self.expression()?;
+            // the expression may have moved on to the next constant segment
+            let arg = if set == Op::SetGlobal {
+                self.intern(name)?
+            } else {
+                arg
+            };
             self.emit_byte_op(set, arg);
+            self.last_assignment = Some(self.current_chunk().count());
+        } else if get == Op::GetLocal && arg == 0 && name == "this" {
+            self.emit_op(Op::GetThis);
         } else {
             self.emit_byte_op(get, arg);
         }
         Ok(())
     }
 
+    // functions nested in a static method have no receiver either
+    fn in_static_method(&self) -> bool {
+        match self.function_type {
+            FunctionType::Static => true,
+            FunctionType::Function => {
+                !self.enclosing.is_null() && self.enclosing.in_static_method()
+            }
+            _ => false,
+        }
+    }
+
     fn super_(&mut self) -> Result<(), String> {
         if self.source.class_depth == 0 {
             return err!("Can't use 'super' outside of a class.");
@@ -480,16 +816,21 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         if self.source.has_super & 1 == 0 {
             return err!("Can't use 'super' in a class with no superclass.");
         }
+        if self.in_static_method() {
+            return err!("Can't use 'super' in a static method.");
+        }
         self.source
             .consume(TokenType::Dot, "Expect '.' after 'super'.")?;
-        let index = self.identifier_constant("Expect superclass method name.")?;
+        let name = self.identifier("Expect superclass method name.")?;
         self.variable("this", false)?;
         if self.source.match_type(TokenType::LeftParen) {
             let arity = self.argument_list()?;
             self.variable("super", false)?;
+            let index = self.intern(name)?;
             self.emit_invoke_op(Op::SuperInvoke, index, arity);
         } else {
             self.variable("super", false)?;
+            let index = self.intern(name)?;
             self.emit_byte_op(Op::GetSuper, index);
         }
         Ok(())
@@ -499,6 +840,24 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         if self.source.class_depth == 0 {
             return err!("Can't use 'this' outside of a class.");
         }
+        if self.in_static_method() {
+            return err!("Can't use 'this' in a static method.");
+        }
+        // the receiver of a method call on this is implied
+        if self.resolve_local("this")? == Some(0) && self.source.match_type(TokenType::Dot) {
+            let name = self.identifier("Expect property name after '.'.")?;
+            if self.source.match_type(TokenType::LeftParen) {
+                let arity = self.argument_list()?;
+                let index = self.intern(name)?;
+                let line = self.source.previous_token.line;
+                self.current_chunk()
+                    .write_cached_invoke(Op::InvokeThis, index, arity, line)?;
+                self.mark_column();
+                return Ok(());
+            }
+            self.emit_op(Op::GetThis);
+            return self.property(name, can_assign);
+        }
         self.variable("this", can_assign)
     }
 
@@ -512,22 +871,33 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         Ok(())
     }
 
-    fn parse_infix(&mut self, token_type: TokenType, can_assign: bool) -> Result<(), String> {
+    fn parse_infix(
+        &mut self,
+        token_type: TokenType,
+        can_assign: bool,
+        left_start: usize,
+    ) -> Result<(), String> {
         match token_type {
             TokenType::LeftParen => self.call(),
+            TokenType::LeftBracket => self.subscript(can_assign),
             TokenType::Dot => self.dot(can_assign),
+            TokenType::DotDot => self.cascade(),
             TokenType::Minus
             | TokenType::Plus
             | TokenType::Slash
             | TokenType::Star
+            | TokenType::StarStar
+            | TokenType::Percent
             | TokenType::BangEqual
             | TokenType::EqualEqual
+            | TokenType::EqualEqualEqual
             | TokenType::Greater
             | TokenType::GreaterEqual
             | TokenType::Less
-            | TokenType::LessEqual => self.binary(),
+            | TokenType::LessEqual => self.binary(left_start),
             TokenType::And => self.and(),
             TokenType::Or => self.or(),
+            TokenType::Question => self.conditional(),
             _ => Ok(()), // unreacheable
         }
     }
@@ -535,9 +905,11 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
     fn parse_prefix(&mut self, token_type: TokenType, can_assign: bool) -> Result<(), String> {
         match token_type {
             TokenType::LeftParen => self.grouping(),
+            TokenType::LeftBracket => self.list(),
             TokenType::Minus | TokenType::Bang => self.unary(token_type),
             TokenType::Identifier => self.variable(self.source.lexeme(), can_assign),
             TokenType::String => self.string(),
+            TokenType::Interpolation => self.interpolation(),
             TokenType::Number => self.number(),
             TokenType::False => {
                 self.emit_op(Op::False);
@@ -560,11 +932,12 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
     fn parse_precedence(&mut self, precedence: Prec) -> Result<(), String> {
         self.source.advance();
         let can_assign = precedence <= Prec::Assignment;
+        let start = self.current_chunk().count();
         self.parse_prefix(self.source.previous_token_type(), can_assign)?;
 
         while precedence <= self.source.current_token.token_type.precedence() {
             self.source.advance();
-            self.parse_infix(self.source.previous_token_type(), can_assign)?;
+            self.parse_infix(self.source.previous_token_type(), can_assign, start)?;
         }
 
         if can_assign && self.source.match_type(TokenType::Equal) {
@@ -574,21 +947,19 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         }
     }
 
-    fn parse_variable(&mut self, error_msg: &str) -> Result<u8, String> {
+    fn parse_variable(&mut self, error_msg: &str) -> Result<&'src str, String> {
         self.source.consume(TokenType::Identifier, error_msg)?;
         let name = self.source.previous_token;
         self.declare_variable(name)?;
-        if self.scope_depth > 0 {
-            Ok(0)
-        } else {
-            self.intern(name.lexeme)
-        }
+        Ok(name.lexeme)
     }
 
-    fn define_variable(&mut self, global: u8) {
+    fn define_variable(&mut self, name: &'src str) -> Result<(), String> {
         if !self.mark_initialized() {
+            let global = self.intern(name)?;
             self.emit_byte_op(Op::DefineGlobal, global)
         }
+        Ok(())
     }
 
     fn expression(&mut self) -> Result<(), String> {
@@ -603,6 +974,24 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
 
     fn function_body(&mut self) -> Result<(), String> {
         self.begin_scope();
+        if self.function_type != FunctionType::Getter {
+            self.parameters()?;
+        }
+        self.source
+            .consume(TokenType::LeftBrace, "Expect '{' before function body")?;
+        self.block()?;
+        if !self.ends_in_return() {
+            self.emit_return();
+        }
+        self.thread_jumps()?;
+        self.verify();
+        while !self.locals.is_empty() {
+            self.pop_local();
+        }
+        Ok(())
+    }
+
+    fn parameters(&mut self) -> Result<(), String> {
         self.source
             .consume(TokenType::LeftParen, "Expect '(' after function name.")?;
         if !self.source.check(TokenType::RightParen) {
@@ -611,8 +1000,20 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
                     return err!("Can't have more than 255 parameters.");
                 }
                 (self.function).arity += 1;
-                let index = self.parse_variable("Expect parameter name")?;
-                self.define_variable(index);
+                let name = self.parse_variable("Expect parameter name")?;
+                let param_name = self.source.heap.intern_copy(name);
+                self.function.param_names.push(param_name);
+                // defaults run at the start of the call, in the new frame
+                if self.source.match_type(TokenType::Equal) {
+                    let entry_point = self.current_chunk().count();
+                    self.function.entry_points.push(entry_point);
+                    self.expression()?;
+                } else if !self.function.entry_points.is_empty() {
+                    return err!("Expect default value after parameters with defaults.");
+                }
+                self.define_variable(name)?;
+                // parameters are exempt from the unused variable lint
+                self.locals.last_mut().unwrap().is_used = true;
                 if !self.source.match_type(TokenType::Comma) {
                     break;
                 }
@@ -620,17 +1021,39 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         }
         self.source
             .consume(TokenType::RightParen, "Expect ')' after parameters.")?;
-        self.source
-            .consume(TokenType::LeftBrace, "Expect '{' before function body")?;
-        self.block()?;
-        self.emit_return();
+        if !self.function.entry_points.is_empty() {
+            let entry_point = self.current_chunk().count();
+            self.function.entry_points.push(entry_point);
+        }
+        Ok(())
+    }
+
+    // instructions may be cut short after an error
+    fn thread_jumps(&mut self) -> Result<(), String> {
+        if self.source.errors.is_empty() {
+            self.current_chunk().thread_jumps()?;
+        }
         Ok(())
     }
 
+    // catch stack imbalances in the generated code
+    fn verify(&mut self) {
+        if cfg!(debug_assertions) && self.source.errors.is_empty() {
+            let base = self.function.min_arity() as usize + 1;
+            if let Err(msg) = self.current_chunk().verify(base) {
+                panic!("{} in {}", msg, *self.function);
+            }
+        }
+    }
+
     fn function(&mut self, function_type: FunctionType) -> Result<(), String> {
-        let name = self.source.lexeme();
+        self.named_function(self.source.lexeme(), function_type)
+    }
+
+    fn named_function(&mut self, name: &str, function_type: FunctionType) -> Result<(), String> {
         let name = self.source.heap.intern_copy(name);
         let mut function = self.source.heap.store(Function::new(Some(name)));
+        function.is_getter = function_type == FunctionType::Getter;
         let mut compiler = Compiler::new(function_type, function, self.source.clone());
         compiler.enclosing = StackRef::new(self);
         let before = function.byte_count();
@@ -642,6 +1065,11 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         self.source
             .heap
             .increase_byte_count(function.byte_count() - before);
+        if upvalues.is_empty() {
+            // without upvalues, one closure can be shared by all evaluations
+            let closure = self.source.heap.store(Closure::new(function));
+            return self.emit_constant(Value::from(closure));
+        }
         let index = self.current_chunk().add_constant(Value::from(function))?;
         self.emit_byte_op(Op::Closure, index);
         for upvalue in upvalues {
@@ -649,20 +1077,41 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
             self.current_chunk()
                 .write(&[upvalue.is_local as u8, upvalue.index], line);
         }
+        self.mark_column();
         Ok(())
     }
 
     fn method(&mut self) -> Result<(), String> {
+        if self.source.match_type(TokenType::LeftBracket) {
+            // the name is only known at runtime
+            self.expression()?;
+            self.source
+                .consume(TokenType::RightBracket, "Expect ']' after method name.")?;
+            self.named_function("[computed]", FunctionType::Method)?;
+            self.emit_op(Op::MethodDynamic);
+            return Ok(());
+        }
+        if self.source.match_type(TokenType::Static) {
+            self.source
+                .consume(TokenType::Identifier, "Expect method name.")?;
+            let name = self.source.lexeme();
+            self.function(FunctionType::Static)?;
+            let intern = self.intern(name)?;
+            self.emit_byte_op(Op::StaticMethod, intern);
+            return Ok(());
+        }
         self.source
             .consume(TokenType::Identifier, "Expect method name.")?;
         let name = self.source.lexeme();
         let function_type = if name == "init" {
             FunctionType::Initializer
+        } else if !self.source.check(TokenType::LeftParen) {
+            FunctionType::Getter
         } else {
             FunctionType::Method
         };
-        let intern = self.intern(name)?;
         self.function(function_type)?;
+        let intern = self.intern(name)?;
         self.emit_byte_op(Op::Method, intern);
         Ok(())
     }
@@ -674,7 +1123,7 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         self.declare_variable(class_name)?;
         let index = self.intern(class_name.lexeme)?;
         self.emit_byte_op(Op::Class, index);
-        self.define_variable(index);
+        self.define_variable(class_name.lexeme)?;
 
         if self.source.class_depth == 127 {
             return err!("Cannot nest classes that deep");
@@ -693,7 +1142,7 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
             }
             self.begin_scope();
             self.add_local(Token::synthetic("super"))?;
-            self.define_variable(0);
+            self.define_variable("super")?;
             self.variable(class_name.lexeme, false)?;
             self.emit_op(Op::Inherit);
             self.source.has_super |= 1;
@@ -726,17 +1175,20 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
     }
 
     fn fun_declaration(&mut self) -> Result<(), String> {
-        let index = self.parse_variable("Expect function name.")?;
+        let name = self.parse_variable("Expect function name.")?;
         self.mark_initialized();
         self.function(FunctionType::Function)?;
-        self.define_variable(index);
+        self.define_variable(name)?;
         Ok(())
     }
 
     fn var_declaration(&mut self) -> Result<(), String> {
-        let index = self.parse_variable("Expect variable name.")?;
+        let name = self.parse_variable("Expect variable name.")?;
         if self.source.match_type(TokenType::Equal) {
             self.expression()?;
+        } else if self.source.strict && self.scope_depth == 0 {
+            // reading the global before assigning it is an error
+            self.emit_op(Op::Undefined)
         } else {
             self.emit_op(Op::Nil)
         }
@@ -744,15 +1196,52 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
             TokenType::Semicolon,
             "Expect ';' after variable declaration.",
         )?;
-        self.define_variable(index);
-        Ok(())
+        self.define_variable(name)
     }
 
     fn expression_statement(&mut self) -> Result<(), String> {
         self.expression()?;
         self.source
             .consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+        let count = self.current_chunk().count();
+        // an assignment at the end can pop the value itself, unless a jump skips it
+        if self.last_assignment == Some(count) && self.last_jump_target != Some(count) {
+            let fused = match Op::try_from(self.current_chunk().read_byte(count - 2))? {
+                Op::SetGlobal => Some(Op::SetGlobalPop),
+                Op::SetLocal => Some(Op::SetLocalPop),
+                _ => None,
+            };
+            if let Some(op) = fused {
+                self.current_chunk().replace_op(count - 2, op);
+                self.last_expression = Some(count);
+                return Ok(());
+            }
+        }
         self.emit_op(Op::Pop);
+        self.last_expression = Some(count + 1);
+        Ok(())
+    }
+
+    // undo the pop of an expression statement that ends the code
+    fn keep_last_expression(&mut self) -> bool {
+        let count = self.current_chunk().count();
+        if self.last_expression != Some(count) || self.last_jump_target == Some(count) {
+            return false;
+        }
+        match Op::try_from(self.current_chunk().read_byte(count - 1)) {
+            Ok(Op::Pop) => self.current_chunk().truncate(count - 1),
+            _ => match Op::try_from(self.current_chunk().read_byte(count - 2)) {
+                Ok(Op::SetGlobalPop) => self.current_chunk().replace_op(count - 2, Op::SetGlobal),
+                Ok(Op::SetLocalPop) => self.current_chunk().replace_op(count - 2, Op::SetLocal),
+                _ => return false,
+            },
+        }
+        true
+    }
+
+    fn patch_jump(&mut self, offset: usize) -> Result<(), String> {
+        self.current_chunk().patch_jump(offset)?;
+        self.last_jump_target = Some(self.current_chunk().count());
         Ok(())
     }
 
@@ -760,12 +1249,14 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         self.begin_scope();
         self.source
             .consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+        let mut loop_variable: Option<u8> = None;
         if !self.source.match_type(TokenType::Semicolon) {
             if self.source.match_type(TokenType::Var) {
-                self.var_declaration()
+                self.var_declaration()?;
+                loop_variable = Some((self.locals.len() - 1) as u8);
             } else {
-                self.expression_statement()
-            }?;
+                self.expression_statement()?;
+            }
         }
         let mut loop_start = self.current_chunk().count();
         let mut exit_jump: Option<usize> = None;
@@ -790,13 +1281,28 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
             self.emit_loop(loop_start)?;
             loop_start = increment_start;
 
-            self.current_chunk().patch_jump(body_jump)?;
+            self.patch_jump(body_jump)?;
         }
 
-        self.statement()?;
+        if let Some(outer) = loop_variable {
+            // each iteration gets its own copy of the loop variable to capture
+            self.begin_scope();
+            self.emit_byte_op(Op::GetLocal, outer);
+            self.add_local(self.locals[outer as usize].name)?;
+            self.mark_initialized();
+            self.locals.last_mut().unwrap().is_used = true;
+            let inner = outer + 1;
+            self.statement()?;
+            self.emit_byte_op(Op::GetLocal, inner);
+            self.emit_byte_op(Op::SetLocal, outer);
+            self.emit_op(Op::Pop);
+            self.end_scope();
+        } else {
+            self.statement()?;
+        }
         self.emit_loop(loop_start)?;
         if let Some(i) = exit_jump {
-            self.current_chunk().patch_jump(i)?;
+            self.patch_jump(i)?;
             self.emit_op(Op::Pop);
         }
         self.end_scope();
@@ -815,16 +1321,71 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         self.statement()?;
         let else_jump = self.emit_jump(Op::Jump);
 
-        self.current_chunk().patch_jump(then_jump)?;
+        self.patch_jump(then_jump)?;
         self.emit_op(Op::Pop);
         if self.source.match_type(TokenType::Else) {
             self.statement()?;
         }
 
-        self.current_chunk().patch_jump(else_jump)?;
+        self.patch_jump(else_jump)?;
+        Ok(())
+    }
+
+    // cases are tried in order and do not fall through
+    fn switch_statement(&mut self) -> Result<(), String> {
+        self.source
+            .consume(TokenType::LeftParen, "Expect '(' after 'switch'.")?;
+        self.expression()?;
+        self.source
+            .consume(TokenType::RightParen, "Expect ')' after value.")?;
+        // the value stays in a hidden local until the end of the switch
+        self.add_local(Token::synthetic("switch"))?;
+        self.mark_initialized();
+        let slot = (self.locals.len() - 1) as u8;
+        self.source
+            .consume(TokenType::LeftBrace, "Expect '{' before switch cases.")?;
+
+        let mut end_jumps = Vec::new();
+        while self.source.match_type(TokenType::Case) {
+            self.emit_byte_op(Op::GetLocal, slot);
+            self.expression()?;
+            self.source
+                .consume(TokenType::Colon, "Expect ':' after case.")?;
+            self.emit_op(Op::Equal);
+            let next_case = self.emit_jump(Op::JumpIfFalse);
+            self.emit_op(Op::Pop);
+            self.case_body();
+            end_jumps.push(self.emit_jump(Op::Jump));
+            self.patch_jump(next_case)?;
+            self.emit_op(Op::Pop);
+        }
+        if self.source.match_type(TokenType::Default) {
+            self.source
+                .consume(TokenType::Colon, "Expect ':' after 'default'.")?;
+            self.case_body();
+            if self.source.check(TokenType::Case) {
+                return err!("The default case must come last.");
+            }
+        }
+        self.source
+            .consume(TokenType::RightBrace, "Expect '}' after switch cases.")?;
+        for jump in end_jumps {
+            self.patch_jump(jump)?;
+        }
         Ok(())
     }
 
+    fn case_body(&mut self) {
+        self.begin_scope();
+        while !matches!(
+            self.source.current_token.token_type,
+            TokenType::Case | TokenType::Default | TokenType::RightBrace | TokenType::End
+        ) {
+            self.declaration();
+        }
+        self.end_scope();
+    }
+
     fn print_statement(&mut self) -> Result<(), String> {
         self.expression()?;
         self.source
@@ -840,7 +1401,6 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
 
         if self.source.match_type(TokenType::Semicolon) {
             self.emit_return();
-            Ok(())
         } else {
             if self.function_type == FunctionType::Initializer {
                 return err!("Can't return a value from an initializer.");
@@ -849,9 +1409,36 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
             self.expression()?;
             self.source
                 .consume(TokenType::Semicolon, "Expect ';' after return value.")?;
+            let count = self.current_chunk().count();
+            // the return stays for callees that cannot reuse the frame
+            if self.last_call == Some(count) && self.last_jump_target != Some(count) {
+                self.current_chunk().replace_op(count - 2, Op::TailCall);
+            }
             self.emit_op(Op::Return);
-            Ok(())
         }
+        self.last_return = Some(self.current_chunk().count());
+        Ok(())
+    }
+
+    // no implicit return is needed after a return that no jump skips
+    fn ends_in_return(&mut self) -> bool {
+        let count = self.current_chunk().count();
+        self.last_return == Some(count) && self.last_jump_target != Some(count)
+    }
+
+    fn yield_statement(&mut self) -> Result<(), String> {
+        match self.function_type {
+            FunctionType::Script => return err!("Can't yield from top-level code."),
+            FunctionType::Initializer => return err!("Can't yield from an initializer."),
+            FunctionType::Getter => return err!("Can't yield from a getter."),
+            _ => (),
+        }
+        self.expression()?;
+        self.source
+            .consume(TokenType::Semicolon, "Expect ';' after yielded value.")?;
+        self.emit_op(Op::Yield);
+        self.function.is_generator = true;
+        Ok(())
     }
 
     fn while_statement(&mut self) -> Result<(), String> {
@@ -867,7 +1454,7 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         self.statement()?;
         self.emit_loop(loop_start)?;
 
-        self.current_chunk().patch_jump(exit_jump)?;
+        self.patch_jump(exit_jump)?;
         self.emit_op(Op::Pop);
         Ok(())
     }
@@ -877,9 +1464,9 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         self.current_chunk().add_constant(value)
     }
 
-    fn identifier_constant(&mut self, error_msg: &str) -> Result<u8, String> {
+    fn identifier(&mut self, error_msg: &str) -> Result<&'src str, String> {
         self.source.consume(TokenType::Identifier, error_msg)?;
-        self.intern(self.source.lexeme())
+        Ok(self.source.lexeme())
     }
 
     fn declaration(&mut self) {
@@ -894,16 +1481,14 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         };
 
         if let Err(msg) = result {
-            println!(
-                "[line: {}, column: {}, lexeme: {}] {}",
-                self.source.previous_token.line,
-                self.source.previous_token.column,
-                self.source.previous_token.lexeme,
-                msg
-            );
-            self.source.error_count += 1;
+            // the bad token was reported already
+            if !self.source.panic_mode {
+                let token = self.source.previous_token;
+                self.source.error_at(token, &msg);
+            }
             self.source.synchronize();
         }
+        self.source.panic_mode = false;
     }
 
     fn statement(&mut self) -> Result<(), String> {
@@ -915,8 +1500,15 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
             self.if_statement()
         } else if self.source.match_type(TokenType::Return) {
             self.return_statement()
+        } else if self.source.match_type(TokenType::Switch) {
+            self.begin_scope();
+            let result = self.switch_statement();
+            self.end_scope();
+            result
         } else if self.source.match_type(TokenType::While) {
             self.while_statement()
+        } else if self.source.match_type(TokenType::Yield) {
+            self.yield_statement()
         } else if self.source.match_type(TokenType::LeftBrace) {
             self.begin_scope();
             let result = self.block();
@@ -932,7 +1524,13 @@ impl<'src, 'hp> Compiler<'src, 'hp> {
         while !self.source.match_type(TokenType::End) {
             self.declaration();
         }
-        self.emit_return();
+        if self.source.eval && self.keep_last_expression() {
+            self.emit_op(Op::Return);
+        } else {
+            self.emit_return();
+        }
+        self.thread_jumps()?;
+        self.verify();
         let replace = self.function;
         self.source
             .heap
@@ -962,27 +1560,71 @@ pub struct Source<'src, 'hp> {
     heap: &'hp mut Heap,
 
     // status
-    error_count: u8,
+    errors: Vec<String>,
+    // errors that follow a bad token are not reported
+    panic_mode: bool,
+
+    // lints are only reported in strict mode
+    strict: bool,
+    warnings: Vec<String>,
+    // the script returns the value of a final expression statement
+    eval: bool,
 }
 
 impl<'src, 'hp> Source<'src, 'hp> {
     pub fn new(source: &'src str, heap: &'hp mut Heap) -> Self {
-        let mut scanner = Scanner::new(source);
-        let current_token = scanner.next();
-        Self {
-            scanner,
-            current_token,
+        let mut source = Self {
+            scanner: Scanner::new(source),
+            current_token: Token::nil(),
             previous_token: Token::nil(),
             has_super: 0,
             class_depth: 0,
             heap,
-            error_count: 0,
-        }
+            errors: Vec::new(),
+            panic_mode: false,
+            strict: false,
+            warnings: Vec::new(),
+            eval: false,
+        };
+        source.advance();
+        source
     }
 
+    fn error_at(&mut self, token: Token, msg: &str) {
+        self.errors.push(format!(
+            "[line: {}, column: {}, lexeme: {}] {}",
+            token.line, token.column, token.lexeme, msg
+        ));
+    }
+
+    fn warn(&mut self, msg: &str) {
+        self.warn_at(self.previous_token, msg);
+    }
+
+    fn warn_at(&mut self, token: Token, msg: &str) {
+        self.warnings.push(format!(
+            "[line: {}, column: {}, lexeme: {}] Warning: {}",
+            token.line, token.column, token.lexeme, msg
+        ));
+    }
+
+    // bad tokens are reported and skipped wherever they appear
     fn advance(&mut self) {
         self.previous_token = self.current_token;
-        self.current_token = self.scanner.next();
+        loop {
+            self.current_token = self.scanner.next();
+            let msg = match self.current_token.token_type {
+                TokenType::BadCharacter => {
+                    format!("Unexpected character '{}'.", self.current_token.lexeme)
+                }
+                TokenType::EndlessComment => String::from("Unterminated block comment."),
+                _ => return,
+            };
+            if !self.panic_mode {
+                self.error_at(self.current_token, &msg);
+            }
+            self.panic_mode = true;
+        }
     }
 
     fn check(&self, token_type: TokenType) -> bool {
@@ -998,12 +1640,12 @@ impl<'src, 'hp> Source<'src, 'hp> {
         }
     }
 
-    fn consume<'b>(&mut self, token_type: TokenType, msg: &'b str) -> Result<(), String> {
+    fn consume(&mut self, token_type: TokenType, msg: &str) -> Result<(), String> {
         if self.check(token_type) {
             self.advance();
             Ok(())
         } else {
-            err!("{}",msg)
+            err!("{}", msg)
         }
     }
 
@@ -1018,7 +1660,8 @@ impl<'src, 'hp> Source<'src, 'hp> {
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
-                | TokenType::Return => {
+                | TokenType::Return
+                | TokenType::Switch => {
                     return;
                 }
                 TokenType::Semicolon => {
@@ -1042,20 +1685,104 @@ impl<'src, 'hp> Source<'src, 'hp> {
     }
 }
 
+// the bits from the highest to the lowest set one in a hex literal, of which
+// an f64 holds up to 53 exactly
+fn significant_bits(hex_digits: &str) -> u32 {
+    let digits = hex_digits.trim_start_matches('0').trim_end_matches('0');
+    let digit = |ch: Option<char>| ch.and_then(|ch| ch.to_digit(16));
+    let (Some(first), Some(last)) = (digit(digits.chars().next()), digit(digits.chars().last()))
+    else {
+        return 0;
+    };
+    4 * digits.len() as u32 - (first.leading_zeros() - 28) - last.trailing_zeros()
+}
+
+// the text between the delimiters of a string part, with single braces and
+// escape sequences replaced
+fn unescape(token: Token) -> Result<String, String> {
+    let mut text = String::new();
+    let (mut line, mut column) = (token.line, token.column + 1);
+    let mut chars = token.lexeme[1..token.lexeme.len() - 1].chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => text.push(match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('"') => '"',
+                Some('\\') => '\\',
+                Some('0') => '\0',
+                Some(other) => {
+                    return err!(
+                        "Invalid escape sequence '\\{}' at line {}, column {}.",
+                        other,
+                        line,
+                        column
+                    )
+                }
+                None => return err!("Unfinished escape sequence."),
+            }),
+            // an empty {} is literal, other braces are doubled
+            '{' if chars.clone().next() == Some('}') => {
+                text.push_str("{}");
+                chars.next();
+            }
+            '{' | '}' => {
+                text.push(ch);
+                chars.next();
+            }
+            _ => text.push(ch),
+        }
+        match ch {
+            '\n' => (line, column) = (line + 1, 1),
+            '\r' => (),
+            '\\' | '{' | '}' => column += 2,
+            _ => column += 1,
+        }
+    }
+    Ok(text)
+}
+
 pub fn compile(source: &str, heap: &mut Heap) -> Result<GC<Function>, String> {
-    let start = Instant::now();
-    let function = heap.store(Function::new(None));
+    // only strict mode warns
+    compile_source(Source::new(source, heap), &mut Vec::new())
+}
+
+pub fn compile_strict(
+    source: &str,
+    heap: &mut Heap,
+    warnings: &mut Vec<String>,
+) -> Result<GC<Function>, String> {
     let mut source = Source::new(source, heap);
+    source.strict = true;
+    compile_source(source, warnings)
+}
+
+pub fn compile_eval(
+    source: &str,
+    heap: &mut Heap,
+    strict: bool,
+    warnings: &mut Vec<String>,
+) -> Result<GC<Function>, String> {
+    let mut source = Source::new(source, heap);
+    source.strict = strict;
+    source.eval = true;
+    compile_source(source, warnings)
+}
+
+// warnings are left for the caller to show
+fn compile_source(mut source: Source, warnings: &mut Vec<String>) -> Result<GC<Function>, String> {
+    let function = source.heap.store(Function::new(None));
     let mut compiler = Compiler::new(FunctionType::Script, function, StackRef::new(&mut source));
-    let obj = compiler.script()?;
-    println!(
-        "Compilation finished in {} ns.",
-        Instant::now().duration_since(start).as_nanos()
-    );
-    match compiler.source.error_count {
+    let result = compiler.script();
+    warnings.append(&mut compiler.source.warnings);
+    let obj = result?;
+    // the errors themselves come first, so callers show them with the summary
+    let errors = compiler.source.errors.join("\n");
+    match compiler.source.errors.len() {
         0 => Ok(obj),
-        1 => err!("There was a compile time error."),
-        more => err!("There were {} compile time errors.", more),
+        1 => err!("{}\nThere was a compile time error.", errors),
+        more => err!("{}\nThere were {} compile time errors.", errors, more),
     }
 }
 
@@ -1073,8 +1800,13 @@ mod tests {
         };
     }
 
+    // functions without upvalues are compiled to closure constants
+    fn function_of(value: Value) -> Option<GC<Function>> {
+        Function::nullable(value).or_else(|| Closure::nullable(value).map(|c| c.function))
+    }
+
     #[test]
-    fn construct_parser<'src>() {
+    fn construct_parser() {
         Source::new("", &mut Heap::new());
     }
 
@@ -1085,6 +1817,42 @@ mod tests {
         assert!(source.match_type(TokenType::End));
     }
 
+    #[test]
+    fn imprecise_integer_literal() {
+        let mut heap = Heap::new();
+        let mut source = Source::new(
+            "print 9007199254740993; print 9007199254740992; print 0.1;
+            print 0x20000000000001; print 0x1FFFFFFFFFFFFF; print 0x100000000000000000;",
+            &mut heap,
+        );
+        source.strict = true;
+        let function = source.heap.store(Function::new(None));
+        let mut compiler =
+            Compiler::new(FunctionType::Script, function, StackRef::new(&mut source));
+        assert!(compiler.script().is_ok());
+        assert_eq!(source.warnings.len(), 2, "{:?}", source.warnings);
+        assert!(source.warnings[0].contains("9007199254740992"));
+        assert!(source.warnings[1].contains("lexeme: 0x20000000000001"));
+        assert!(source.warnings[1].contains("9007199254740992"));
+    }
+
+    #[test]
+    fn unused_variables() {
+        let test = "{ var x = 1; var _y = 2; var z = 3; print z; }
+        fun f(a) { var b; }
+        for (var i = 0; i < 1; i = i + 1) {}";
+        let mut heap = Heap::new();
+        let mut source = Source::new(test, &mut heap);
+        source.strict = true;
+        let function = source.heap.store(Function::new(None));
+        let mut compiler =
+            Compiler::new(FunctionType::Script, function, StackRef::new(&mut source));
+        assert!(compiler.script().is_ok());
+        assert_eq!(source.warnings.len(), 2, "{:?}", source.warnings);
+        assert!(source.warnings[0].contains("'x'"));
+        assert!(source.warnings[1].contains("'b'"));
+    }
+
     #[test]
     fn compile_empty_string() {
         let result = compile("", &mut Heap::new());
@@ -1279,6 +2047,409 @@ mod tests {
         disassemble!(&result.unwrap().chunk);
     }
 
+    #[test]
+    fn fold_comparisons() {
+        let test =
+            "print 1 < 2; print nil == false; print (1 < 2) == true; var a = 1; print a < 2;";
+        let mut heap = Heap::new();
+        let result = compile(test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let chunk = &result.unwrap().chunk;
+        disassemble!(chunk);
+        let expected = [
+            Op::True,
+            Op::Print,
+            Op::False,
+            Op::Print,
+            Op::True,
+            Op::Print,
+        ];
+        for (i, op) in expected.iter().enumerate() {
+            assert_eq!(chunk.read_byte(i), *op as u8);
+        }
+        assert_eq!(chunk.read_byte(6), Op::SmallInt as u8);
+    }
+
+    #[test]
+    fn thread_jumps() {
+        let test = "
+        var a = true; var b = false;
+        if (a) { if (b) print 1; else print 2; } else print 3;
+        if (a) { if (b) print 1; } else print 3;
+        print a and b or a;";
+        let mut heap = Heap::new();
+        let result = compile(test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let chunk = &result.unwrap().chunk;
+        disassemble!(chunk);
+        let mut offset = 0;
+        let mut jumps = 0;
+        while offset < chunk.count() {
+            let op = chunk.read_byte(offset);
+            if op == Op::Jump as u8 || op == Op::JumpIfFalse as u8 {
+                jumps += 1;
+                let target = chunk.jump_target(offset);
+                assert_ne!(chunk.read_byte(target), Op::Jump as u8, "at {}", offset);
+            }
+            offset += chunk.instruction_length(offset);
+        }
+        assert!(jumps > 0);
+    }
+
+    #[test]
+    fn small_integers_need_no_constants() {
+        let test = "fun f() { return 0; } fun g() { return 0 + 255; } print 256 + 0.5;";
+        let mut heap = Heap::new();
+        let result = compile(test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let script = result.unwrap();
+        for &constant in &script.chunk.constants {
+            if let Some(function) = function_of(constant) {
+                assert!(function.chunk.constants.is_empty());
+            }
+        }
+        assert!(script.chunk.constants.contains(&Value::from(256.0)));
+        assert!(!script.chunk.constants.contains(&Value::from(0.0)));
+    }
+
+    #[test]
+    fn malformed_numbers() {
+        let mut heap = Heap::new();
+        for (test, error) in [
+            (
+                "print 1 +\n  2e;",
+                "[line: 2, column: 3, lexeme: 2e] Malformed number '2e'.",
+            ),
+            (
+                "print 1.5e-;",
+                "[line: 1, column: 7, lexeme: 1.5e-] Malformed number '1.5e-'.",
+            ),
+            (
+                "print 0x;",
+                "[line: 1, column: 7, lexeme: 0x] Malformed number '0x'.",
+            ),
+            (
+                "print 1 +\n 0xg;",
+                "[line: 2, column: 2, lexeme: 0xg] Malformed number '0xg'.",
+            ),
+        ] {
+            let result = compile(test, &mut heap);
+            assert!(
+                result.as_ref().is_err_and(|msg| msg.contains(error)),
+                "{:?}",
+                result.err()
+            );
+        }
+    }
+
+    #[test]
+    fn columns() {
+        let test = "var a = 1;\nprint   a + 2;";
+        let mut heap = Heap::new();
+        let result = compile(test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let chunk = &result.unwrap().chunk;
+        disassemble!(chunk);
+        let offset = chunk
+            .instruction_offsets()
+            .into_iter()
+            .find(|&offset| chunk.read_byte(offset) == Op::GetGlobal as u8)
+            .unwrap();
+        assert_eq!(chunk.location(offset), (2, 9));
+        assert_eq!(chunk.columns.len(), chunk.count());
+    }
+
+    #[test]
+    fn line_table() {
+        let test = "fun f() {\n  var a = 1;\n  print a;\n}";
+        let mut heap = Heap::new();
+        let result = compile(test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let function = function_of(result.unwrap().chunk.constants[0]).unwrap();
+        let chunk = &function.chunk;
+        disassemble!(chunk);
+        assert_eq!(
+            chunk.line_table().collect::<Vec<_>>(),
+            [(2, 2), (3, 3), (4, 2)]
+        );
+        assert_eq!(chunk.instruction_offsets(), [0, 2, 4, 5, 6]);
+    }
+
+    #[test]
+    fn no_return_after_return() {
+        let test = "fun f(x) { return x; } fun g(x) { if (x) return 1; else return 2; }";
+        let mut heap = Heap::new();
+        let result = compile(test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let script = result.unwrap();
+        let f = function_of(script.chunk.constants[0]).unwrap();
+        disassemble!(&f.chunk);
+        assert_eq!(f.chunk.instruction_offsets(), [0, 2]);
+        assert_eq!(f.chunk.read_byte(2), Op::Return as u8);
+        // the jump over the else branch still needs a target
+        let g = function_of(script.chunk.constants[2]).unwrap();
+        let count = g.chunk.count();
+        assert_eq!(g.chunk.read_byte(count - 2), Op::Nil as u8);
+        assert_eq!(g.chunk.read_byte(count - 1), Op::Return as u8);
+    }
+
+    #[test]
+    fn pop_locals_together() {
+        let mut test = String::from("{ var captured; fun f() { return captured; }");
+        for i in 0..200 {
+            test.push_str(&format!(" var a{} = {};", i, i));
+        }
+        test.push_str(" }");
+        let mut heap = Heap::new();
+        let result = compile(&test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let chunk = &result.unwrap().chunk;
+        disassemble!(chunk);
+        let ops: Vec<u8> = chunk
+            .instruction_offsets()
+            .into_iter()
+            .map(|offset| chunk.read_byte(offset))
+            .collect();
+        let pop_n = chunk.instruction_offsets()[ops.len() - 4];
+        assert_eq!(chunk.read_byte(pop_n), Op::PopN as u8);
+        // the function and the locals after it
+        assert_eq!(chunk.read_byte(pop_n + 1), 201);
+        assert_eq!(ops[ops.len() - 3], Op::CloseUpvalue as u8);
+        assert!(!ops.contains(&(Op::Pop as u8)));
+    }
+
+    #[test]
+    fn parameter_names() {
+        let mut heap = Heap::new();
+        let result = compile("fun f(a, b) {} fun g() {}", &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let script = result.unwrap();
+        let f = function_of(script.chunk.constants[0]).unwrap();
+        let expected = [heap.intern_copy("a"), heap.intern_copy("b")];
+        assert_eq!(f.param_names, expected);
+        let g = function_of(script.chunk.constants[2]).unwrap();
+        assert!(g.param_names.is_empty());
+        assert!(script.param_names.is_empty());
+    }
+
+    #[test]
+    fn yield_outside_functions() {
+        assert!(compile("yield 1;", &mut Heap::new()).is_err());
+        assert!(compile("class A { init() { yield 1; } }", &mut Heap::new()).is_err());
+        let mut heap = Heap::new();
+        let result = compile("fun f() { yield 1; } fun g() {}", &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let constants = &result.unwrap().chunk.constants;
+        let generators: Vec<bool> = constants
+            .iter()
+            .filter_map(|&value| function_of(value))
+            .map(|function| function.is_generator)
+            .collect();
+        assert_eq!(generators, [true, false]);
+    }
+
+    #[test]
+    fn strings_share_constants_with_names() {
+        let mut heap = Heap::new();
+        let result = compile("var foo = \"foo\"; print foo; print \"foo\";", &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let constants = &result.unwrap().chunk.constants;
+        assert_eq!(constants.len(), 1);
+        assert_eq!(constants[0], Value::from(heap.intern_copy("foo")));
+    }
+
+    #[test]
+    fn assignment_statements_pop() {
+        let mut heap = Heap::new();
+        let test = "var x; x = 5; { var y; y = x; } var a; a and (x = 1); x = 2 + x;";
+        let result = compile(test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let chunk = &result.unwrap().chunk;
+        disassemble!(chunk);
+        let ops: Vec<u8> = chunk
+            .instruction_offsets()
+            .into_iter()
+            .map(|offset| chunk.read_byte(offset))
+            .collect();
+        let count = |op: Op| ops.iter().filter(|&&byte| byte == op as u8).count();
+        assert_eq!(count(Op::SetGlobalPop), 2);
+        assert_eq!(count(Op::SetLocalPop), 1);
+        assert_eq!(count(Op::SetGlobal), 1);
+    }
+
+    #[test]
+    fn closures_without_upvalues() {
+        let test = "fun f() {} fun g() { var x; fun h() { return x; } }";
+        let mut heap = Heap::new();
+        let result = compile(test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let chunk = &result.unwrap().chunk;
+        disassemble!(chunk);
+        assert_eq!(chunk.read_byte(0), Op::Constant as u8);
+        let f = Closure::nullable(chunk.read_constant(1)).unwrap();
+        assert!(f.upvalues.is_empty());
+        let g = function_of(chunk.constants[2]).unwrap();
+        assert!(g
+            .chunk
+            .instruction_offsets()
+            .into_iter()
+            .any(|offset| g.chunk.read_byte(offset) == Op::Closure as u8));
+    }
+
+    #[test]
+    fn get_this() {
+        let test = "class A { m() { return this.x; } }";
+        let mut heap = Heap::new();
+        let result = compile(test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let script = result.unwrap();
+        let method = script
+            .chunk
+            .constants
+            .iter()
+            .find_map(|&value| function_of(value))
+            .unwrap();
+        disassemble!(&method.chunk);
+        assert_eq!(method.chunk.read_byte(0), Op::GetThis as u8);
+        assert_eq!(method.chunk.read_byte(1), Op::GetProperty as u8);
+    }
+
+    #[test]
+    fn invoke_this() {
+        let test = "class A { m(a) { return this.n(a, 1); } }";
+        let mut heap = Heap::new();
+        let result = compile(test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let script = result.unwrap();
+        let method = script
+            .chunk
+            .constants
+            .iter()
+            .find_map(|&value| function_of(value))
+            .unwrap();
+        disassemble!(&method.chunk);
+        assert_eq!(method.chunk.read_byte(0), Op::GetLocal as u8);
+        assert_eq!(method.chunk.read_byte(2), Op::SmallInt as u8);
+        assert_eq!(method.chunk.read_byte(4), Op::InvokeThis as u8);
+        assert_eq!(method.chunk.read_byte(6), 2);
+        assert_eq!(method.chunk.read_byte(9), Op::Return as u8);
+    }
+
+    #[test]
+    fn typed_additions() {
+        let cases = [
+            ("1 + 2;", Op::AddNum),
+            ("\"a\" + \"b\";", Op::AddStr),
+            ("x + \"b\";", Op::AddStr),
+            ("(x - 1) + y;", Op::AddNum),
+            ("x + y;", Op::Add),
+            ("(x and 1) + y;", Op::Add),
+            ("true + x;", Op::Add),
+        ];
+        for (test, op) in cases {
+            let mut heap = Heap::new();
+            let result = compile(test, &mut heap);
+            assert!(result.is_ok(), "{}", result.unwrap_err());
+            let chunk = &result.unwrap().chunk;
+            disassemble!(chunk);
+            let ops = chunk
+                .instruction_offsets()
+                .into_iter()
+                .map(|offset| chunk.read_byte(offset))
+                .collect::<Vec<_>>();
+            assert!(ops.contains(&(op as u8)), "{}", test);
+        }
+    }
+
+    #[test]
+    fn escape_sequences() {
+        let test = r#"print "a\nb\t\"c\"\\\0{{}}\r";"#;
+        let mut heap = Heap::new();
+        let result = compile(test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let value = result.unwrap().chunk.constants[0];
+        assert_eq!(
+            Loxtr::nullable(value).unwrap().as_ref(),
+            "a\nb\t\"c\"\\\0{}\r"
+        );
+        assert!(compile("print \"\\q\";", &mut heap).is_err());
+        let token = Scanner::new("\"a\nb{{\\q\"").next();
+        let msg = unescape(token).unwrap_err();
+        assert!(msg.contains("'\\q' at line 2, column 4"), "{}", msg);
+    }
+
+    #[test]
+    fn unexpected_character() {
+        let mut heap = Heap::new();
+        for (test, errors) in [
+            (
+                "print 1 + @;",
+                "[line: 1, column: 11, lexeme: @] Unexpected character '@'.",
+            ),
+            (
+                "\n  @",
+                "[line: 2, column: 3, lexeme: @] Unexpected character '@'.",
+            ),
+            (
+                "print 1 @ 2;",
+                "[line: 1, column: 9, lexeme: @] Unexpected character '@'.",
+            ),
+            (
+                "print 1 /* open",
+                "[line: 1, column: 9, lexeme: /* open] Unterminated block comment.",
+            ),
+            // the rest of the declaration is not reported
+            (
+                "print 1 @ 2 @ 3;\nprint 4",
+                "[line: 1, column: 9, lexeme: @] Unexpected character '@'.\n\
+                 [line: 2, column: 7, lexeme: 4] Expect ';' after value.",
+            ),
+        ] {
+            let msg = compile(test, &mut heap).unwrap_err();
+            assert!(msg.starts_with(&format!("{}\nThere w", errors)), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn shared_string_constants() {
+        let test = "fun f() { print \"error\"; fun g() { print \"error\"; } }";
+        let mut heap = Heap::new();
+        let result = compile(test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let f = function_of(result.unwrap().chunk.constants[0]).unwrap();
+        let g = f
+            .chunk
+            .constants
+            .iter()
+            .find_map(|&value| function_of(value));
+        let strings = |function: GC<Function>| {
+            function
+                .chunk
+                .constants
+                .iter()
+                .filter_map(|&value| Loxtr::nullable(value))
+                .collect::<Vec<_>>()
+        };
+        // one string, referenced from both pools
+        assert_eq!(strings(f).len(), 1);
+        assert_eq!(strings(f), strings(g.unwrap()));
+    }
+
+    #[test]
+    fn four_hundred_constants() {
+        let mut test = String::from("fun f() {\n");
+        for i in 0..400 {
+            test.push_str(&format!("print {}.5;\n", i));
+        }
+        test.push_str("}\n");
+        let mut heap = Heap::new();
+        let result = compile(&test, &mut heap);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let function = function_of(result.unwrap().chunk.constants[0]).unwrap();
+        assert_eq!(function.chunk.constants.len(), 400);
+        disassemble!(&function.chunk);
+    }
+
     #[test]
     fn super_call() {
         let test = "
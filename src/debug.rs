@@ -33,9 +33,16 @@ impl<'src> Disassembler<'src> {
                 }
             };
             match op_code {
-                Op::Call | Op::GetLocal | Op::GetUpvalue | Op::SetLocal | Op::SetUpvalue => {
-                    self.byte()
-                }
+                Op::BuildList
+                | Op::Call
+                | Op::GetLocal
+                | Op::GetUpvalue
+                | Op::PopN
+                | Op::SetLocal
+                | Op::SetLocalPop
+                | Op::SetUpvalue
+                | Op::SmallInt
+                | Op::TailCall => self.byte(),
                 Op::Class
                 | Op::Closure
                 | Op::Constant
@@ -45,10 +52,18 @@ impl<'src> Disassembler<'src> {
                 | Op::GetSuper
                 | Op::Method
                 | Op::SetGlobal
-                | Op::SetProperty => self.constant(),
-                Op::Invoke | Op::SuperInvoke => self.invoke(),
-                Op::Jump | Op::JumpIfFalse => self.jump_forward(),
-                Op::Loop => self.jump_back(),
+                | Op::SetGlobalPop
+                | Op::SetProperty
+                | Op::StaticMethod => self.constant(),
+                Op::Invoke | Op::InvokeThis => {
+                    self.invoke();
+                    self.cache();
+                }
+                Op::SuperInvoke => self.invoke(),
+                Op::Jump | Op::JumpIfFalse | Op::JumpLong | Op::JumpIfFalseLong => {
+                    self.jump_forward()
+                }
+                Op::Loop | Op::LoopLong => self.jump_back(),
                 _ => (),
             }
             println!(";")
@@ -59,23 +74,27 @@ impl<'src> Disassembler<'src> {
         self.ip += 1;
     }
     fn constant(&mut self) {
-        print!(" {}", self.chunk.read_constant(self.ip));
+        print!(" {}", self.chunk.read_constant(self.ip).to_repr());
         self.ip += 1;
     }
     fn invoke(&mut self) {
         print!(
             " {} ({})",
-            self.chunk.read_constant(self.ip),
+            self.chunk.read_constant(self.ip).to_repr(),
             self.chunk.read_byte(self.ip + 1)
         );
         self.ip += 2;
     }
+    fn cache(&mut self) {
+        print!(" [{}]", self.chunk.read_short(self.ip));
+        self.ip += 2;
+    }
     fn jump_forward(&mut self) {
-        print!(" {}", self.ip + self.chunk.read_short(self.ip) as usize);
+        print!(" {}", self.chunk.jump_target(self.ip - 1));
         self.ip += 2;
     }
     fn jump_back(&mut self) {
-        print!(" {}", self.ip - self.chunk.read_short(self.ip) as usize);
+        print!(" {}", self.chunk.loop_target(self.ip - 1));
         self.ip += 2;
     }
 }
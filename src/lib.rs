@@ -0,0 +1,14 @@
+#[cfg(any(test, feature = "trace"))]
+pub mod debug;
+
+#[macro_use]
+mod common;
+pub mod chunk;
+mod compiler;
+pub mod loxtr;
+pub mod table;
+
+pub mod memory;
+pub mod object;
+mod scanner;
+pub mod vm;
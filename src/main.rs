@@ -1,60 +1,325 @@
-use std::{env, fs, io, process::exit};
+use std::{
+    env, fs,
+    io::{self, Write},
+    process::exit,
+};
 
-use memory::Heap;
+use rlox::{
+    memory::Heap,
+    vm::{InterpretError, VM},
+};
 
-use crate::vm::VM;
+const REPL_HELP: &str = "Enter Lox code to run it, or a command:
+  .help   show this message
+  .clear  forget all global variables
+  .gc     collect garbage and show heap statistics
+  .exit   quit";
 
-#[cfg(feature = "trace")]
-mod debug;
+// first words of statements, which have no value to show
+const STATEMENT_KEYWORDS: [&str; 10] = [
+    "class", "for", "fun", "if", "print", "return", "switch", "var", "while", "yield",
+];
 
-#[macro_use]
-mod common;
-mod chunk;
-mod compiler;
-mod loxtr;
-mod table;
+// the line up to a comment that is not in a string
+fn without_comment(line: &str) -> &str {
+    let bytes = line.as_bytes();
+    let mut in_string = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => in_string = !in_string,
+            b'\\' if in_string => i += 1,
+            b'/' if !in_string && bytes.get(i + 1) == Some(&b'/') => return &line[..i],
+            _ => (),
+        }
+        i += 1;
+    }
+    line
+}
 
-mod memory;
-mod object;
-mod scanner;
-mod vm;
+// input without a closing ';' or '}' gets one, and is shown when it is an
+// expression
+fn complete_line(line: &str) -> (String, bool) {
+    let code = without_comment(line).trim();
+    if code.is_empty() || code.ends_with([';', '}']) {
+        return (String::from(line), false);
+    }
+    let first_word = code
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default();
+    (
+        format!("{};", code),
+        !STATEMENT_KEYWORDS.contains(&first_word),
+    )
+}
 
-fn repl(vm: &mut VM) {
-    loop {
-        print!("> ");
+// handle a line of input, false to stop the repl
+fn repl_line(line: &str, vm: &mut VM, out: &mut impl Write) -> bool {
+    // losing repl output is no reason to stop
+    match line.trim() {
+        ".help" => {
+            let _ = writeln!(out, "{}", REPL_HELP);
+        }
+        ".clear" => vm.reset_globals(),
+        ".gc" => {
+            let (objects, bytes) = vm.heap_stats();
+            vm.collect_garbage();
+            let (live_objects, live_bytes) = vm.heap_stats();
+            let _ = writeln!(
+                out,
+                "objects: {} -> {}, bytes: {} -> {}",
+                objects, live_objects, bytes, live_bytes
+            );
+        }
+        ".exit" => return false,
+        command if command.starts_with('.') => {
+            eprintln!("Unknown command '{}', try .help", command)
+        }
+        _ => {
+            let result = match complete_line(line) {
+                (source, true) => vm.eval(&source).map(|value| {
+                    let _ = writeln!(out, "{}", value);
+                }),
+                (source, false) => vm.interpret(&source),
+            };
+            // the vm reports runtime errors itself
+            if let Err(InterpretError::Compile(msg)) = result {
+                eprintln!("{}", msg);
+            }
+        }
+    }
+    true
+}
+
+// lets a host plug in a line editor with history
+trait ReplInput {
+    // none at the end of the input
+    fn read_line(&mut self, prompt: &str) -> Option<String>;
+}
+
+struct StdinInput;
+
+impl ReplInput for StdinInput {
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        print!("{}", prompt);
+        io::stdout().flush().ok()?;
         let mut buf = String::new();
-        if io::stdin().read_line(&mut buf).is_err() {
-            println!();
-            return;
+        match io::stdin().read_line(&mut buf) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(buf),
         }
-        if buf == "\r\n" {
-            println!();
-            return;
+    }
+}
+
+fn repl(input: &mut impl ReplInput, vm: &mut VM) {
+    while let Some(line) = input.read_line("> ") {
+        if line == "\r\n" || !repl_line(&line, vm, &mut io::stdout()) {
+            break;
         }
-        if let Err(msg) = vm.interpret(&buf) {
+    }
+    println!();
+}
+
+fn read_file(file_path: &str) -> String {
+    fs::read_to_string(file_path)
+        .unwrap_or_else(|_| panic!("Couldn't read the file '{}'", file_path))
+}
+
+fn exit_code(result: Result<(), InterpretError>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(InterpretError::Compile(msg)) => {
             eprintln!("{}", msg);
+            65
         }
+        Err(InterpretError::Runtime(_)) => 70,
     }
 }
 
-fn run_file(file_path: &str, vm: &mut VM) {
-    let source = fs::read_to_string(file_path)
-        .unwrap_or_else(|_| panic!("Couldn't read the file '{}'", file_path));
-    if let Err(msg) = vm.interpret(&source) {
-        eprintln!("{}", msg);
-        exit(70)
+fn run_file(file_path: &str, vm: &mut VM) -> i32 {
+    exit_code(vm.interpret(&read_file(file_path)))
+}
+
+fn check_file(file_path: &str, vm: &mut VM) -> i32 {
+    exit_code(vm.check(&read_file(file_path)))
+}
+
+fn dispatch(args: &[String], vm: &mut VM) -> i32 {
+    match args {
+        [_] => {
+            repl(&mut StdinInput, vm);
+            0
+        }
+        [_, flag, path] if flag == "--check" => check_file(path, vm),
+        [_, flag, path] if flag == "--quiet" => {
+            vm.set_output_enabled(false);
+            run_file(path, vm)
+        }
+        [_, flag, path] if flag == "--strict" => {
+            vm.set_strict(true);
+            run_file(path, vm)
+        }
+        [_, path] => run_file(path, vm),
+        _ => {
+            eprintln!("Usage: rlox [--check|--quiet|--strict] [path]\n");
+            64
+        }
     }
 }
 
 fn main() {
     let mut vm = VM::new(Heap::new());
     let args: Vec<String> = env::args().collect();
-    match args.len() {
-        1 => repl(&mut vm),
-        2 => run_file(&args[1], &mut vm),
-        _ => {
-            eprintln!("Usage: rlox [path]\n");
-            exit(64);
+    exit(dispatch(&args, &mut vm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&arg| String::from(arg)).collect()
+    }
+
+    #[test]
+    fn check_valid_file() {
+        let mut vm = VM::new(Heap::new());
+        let code = dispatch(&args(&["rlox", "--check", "test.lox"]), &mut vm);
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    fn check_invalid_file() {
+        let mut vm = VM::new(Heap::new());
+        let code = dispatch(
+            &args(&["rlox", "--check", "test/print/missing_argument.lox"]),
+            &mut vm,
+        );
+        assert_eq!(code, 65);
+    }
+
+    #[test]
+    fn run_file_with_compile_error() {
+        let mut vm = VM::new(Heap::new());
+        let code = dispatch(&args(&["rlox", "test/print/missing_argument.lox"]), &mut vm);
+        assert_eq!(code, 65);
+    }
+
+    #[test]
+    fn run_file_with_runtime_error() {
+        let mut vm = VM::new(Heap::new());
+        let code = dispatch(&args(&["rlox", "test/operator/add_bool_nil.lox"]), &mut vm);
+        assert_eq!(code, 70);
+    }
+
+    #[test]
+    fn run_file_quietly() {
+        let mut vm = VM::new(Heap::new());
+        let code = dispatch(&args(&["rlox", "--quiet", "test.lox"]), &mut vm);
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    fn run_file_strictly() {
+        let mut vm = VM::new(Heap::new());
+        let code = dispatch(&args(&["rlox", "--strict", "test.lox"]), &mut vm);
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    fn repl_commands() {
+        let mut vm = VM::new(Heap::new());
+        let mut out = Vec::new();
+        assert!(repl_line(".help\n", &mut vm, &mut out));
+        assert!(String::from_utf8_lossy(&out).starts_with("Enter Lox code"));
+        assert!(repl_line(".unknown\n", &mut vm, &mut out));
+        assert!(repl_line("var a = \"a\" + \"b\";\n", &mut vm, &mut out));
+        assert!(vm.interpret("print a;").is_ok());
+
+        assert!(repl_line(
+            "var b = \"c\" + \"d\"; b = nil;\n",
+            &mut vm,
+            &mut out
+        ));
+        let (objects, _) = vm.heap_stats();
+        assert!(repl_line(".gc\n", &mut vm, &mut out));
+        assert!(vm.heap_stats().0 < objects);
+        assert!(vm.interpret("print a;").is_ok());
+
+        assert!(repl_line(".clear\n", &mut vm, &mut out));
+        assert!(vm.interpret("print a;").is_err());
+        assert!(vm.interpret("print clock;").is_ok());
+        assert!(!repl_line(".exit\n", &mut vm, &mut out));
+    }
+
+    #[test]
+    fn complete_lines() {
+        let line = |code: &str, shown| (String::from(code), shown);
+        assert_eq!(complete_line("1 + 2\n"), line("1 + 2;", true));
+        assert_eq!(complete_line("print 1\n"), line("print 1;", false));
+        assert_eq!(
+            complete_line("var a = 1 // one\n"),
+            line("var a = 1;", false)
+        );
+        assert_eq!(
+            complete_line("\"a // \\\"b\" // c\n"),
+            line("\"a // \\\"b\";", true)
+        );
+        assert_eq!(complete_line("print 1;\n"), line("print 1;\n", false));
+        assert_eq!(complete_line("fun f() {}\n"), line("fun f() {}\n", false));
+        assert_eq!(complete_line("// note\n"), line("// note\n", false));
+        assert_eq!(complete_line("\n"), line("\n", false));
+    }
+
+    #[test]
+    fn repl_echo() {
+        let mut vm = VM::new(Heap::new());
+        let mut echo = Vec::new();
+        for input in [
+            "var a = 1 + 2\n",
+            "a * 2\n",
+            "// a comment\n",
+            "print a\n",
+            "a // three\n",
+            "\"a\" + \"//\"\n",
+        ] {
+            assert!(repl_line(input, &mut vm, &mut echo), "{}", input);
+        }
+        assert_eq!(String::from_utf8(echo).unwrap(), "6\n3\na//\n");
+        assert!(vm.interpret("print a;").is_ok());
+    }
+
+    struct ScriptedInput(Vec<&'static str>);
+
+    impl ReplInput for ScriptedInput {
+        fn read_line(&mut self, _prompt: &str) -> Option<String> {
+            if self.0.is_empty() {
+                None
+            } else {
+                Some(String::from(self.0.remove(0)))
+            }
         }
     }
+
+    #[test]
+    fn scripted_repl() {
+        let mut vm = VM::new(Heap::new());
+        let mut input = ScriptedInput(vec!["var a = 1;\n", "var b = a + 1;\n"]);
+        repl(&mut input, &mut vm);
+        assert!(input.0.is_empty());
+        assert!(vm.interpret("print b;").is_ok());
+
+        let mut input = ScriptedInput(vec![".exit\n", "var c = 3;\n"]);
+        repl(&mut input, &mut vm);
+        assert_eq!(input.0, ["var c = 3;\n"]);
+        assert!(vm.interpret("print c;").is_err());
+    }
+
+    #[test]
+    fn bad_usage() {
+        let mut vm = VM::new(Heap::new());
+        let code = dispatch(&args(&["rlox", "--lint", "a.lox", "b.lox"]), &mut vm);
+        assert_eq!(code, 64);
+    }
 }
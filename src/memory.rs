@@ -1,11 +1,14 @@
 use std::{
+    collections::HashSet,
     fmt::Display,
     ops::{Deref, DerefMut},
 };
 
 use crate::{
     loxtr::Loxtr,
-    object::{BoundMethod, Class, Closure, Function, Instance, Native, Upvalue, Value},
+    object::{
+        BoundMethod, Class, Closure, Coroutine, Function, Instance, List, Native, Upvalue, Value,
+    },
     table::Table,
 };
 
@@ -17,11 +20,13 @@ pub enum Kind {
     BoundMethod = 1, // different (better?) miri errors
     Class,
     Closure,
+    Coroutine,
     Function,
     Instance,
     Native,
     String,
     Upvalue,
+    List,
 }
 
 // struct -> seg fault
@@ -33,16 +38,18 @@ macro_rules! as_gc {
             Kind::BoundMethod => BoundMethod::as_gc(&$handle).$method($($args)*),
             Kind::Class => Class::as_gc(&$handle).$method($($args)*),
             Kind::Closure => Closure::as_gc(&$handle).$method($($args)*),
+            Kind::Coroutine => Coroutine::as_gc(&$handle).$method($($args)*),
             Kind::Function => Function::as_gc(&$handle).$method($($args)*),
             Kind::Instance => Instance::as_gc(&$handle).$method($($args)*),
             Kind::Native => Native::as_gc(&$handle).$method($($args)*),
             Kind::String => Loxtr::as_gc(&$handle).$method($($args)*),
             Kind::Upvalue => Upvalue::as_gc(&$handle).$method($($args)*),
+            Kind::List => List::as_gc(&$handle).$method($($args)*),
         }
     };
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Handle {
     // Obj<()> did not work! perhaps it is an zero size type issue
     ptr: *mut Obj<u8>,
@@ -104,7 +111,7 @@ impl<T: Traceable> Copy for GC<T> {}
 
 impl<T: Traceable> Clone for GC<T> {
     fn clone(&self) -> Self {
-        Self { ptr: self.ptr }
+        *self
     }
 }
 
@@ -175,18 +182,58 @@ pub struct Heap {
     string_pool: Table<()>,
     byte_count: usize,
     next_gc: usize,
+    growth_factor: f64,
+}
+
+const INITIAL_CAPACITY: usize = 1 << 12;
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Heap {
     pub fn new() -> Self {
+        Self::with_threshold(1 << 20)
+    }
+
+    // the first collection happens once the byte count passes next_gc
+    pub fn with_threshold(next_gc: usize) -> Self {
         Self {
-            handles: Vec::with_capacity(1 << 12),
+            handles: Vec::with_capacity(INITIAL_CAPACITY),
             string_pool: Table::new(),
             byte_count: 0,
-            next_gc: 1 << 20,
+            next_gc,
+            growth_factor: 2.0,
         }
     }
 
+    // the threshold is multiplied by this after every collection
+    pub fn set_growth_factor(&mut self, growth_factor: f64) {
+        self.growth_factor = growth_factor.max(1.0);
+    }
+
+    pub fn next_gc(&self) -> usize {
+        self.next_gc
+    }
+
+    pub fn interned_strings(&self) -> impl Iterator<Item = &str> {
+        self.string_pool.keys().map(|key| key.as_ref())
+    }
+
+    pub fn intern_count(&self) -> usize {
+        self.string_pool.keys().count()
+    }
+
+    pub fn byte_count(&self) -> usize {
+        self.byte_count
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.handles.len()
+    }
+
     pub fn increase_byte_count(&mut self, diff: usize) {
         self.byte_count += diff;
     }
@@ -242,8 +289,9 @@ impl Heap {
             self.sweep_at_capacity()
         } else {
             self.sweep_in_place();
+            self.shrink();
         }
-        self.next_gc *= 2;
+        self.next_gc = (self.next_gc as f64 * self.growth_factor) as usize;
         #[cfg(feature = "log_gc")]
         {
             println!("-- gc end");
@@ -258,6 +306,39 @@ impl Heap {
         }
     }
 
+    // check that no object is marked between collections and that all
+    // references, including those from the string pool, stay in the heap
+    pub fn validate(&self) -> Result<(), String> {
+        let objects: HashSet<*mut Obj<u8>> = self.handles.iter().map(|handle| handle.ptr).collect();
+        let mut references = Vec::new();
+        for handle in self.handles.iter() {
+            if handle.is_marked() {
+                return err!(
+                    "{:?} at {:?} is marked outside a collection.",
+                    handle.kind(),
+                    handle.ptr
+                );
+            }
+            as_gc!(handle, trace(&mut references));
+            for reference in references.drain(..) {
+                if !objects.contains(&reference.ptr) {
+                    return err!(
+                        "{:?} at {:?} refers to {:?}, which is not in the heap.",
+                        handle.kind(),
+                        handle.ptr,
+                        reference.ptr
+                    );
+                }
+            }
+        }
+        for key in self.string_pool.keys() {
+            if !objects.contains(&Handle::from(*key).ptr) {
+                return err!("Interned string '{}' is not in the heap.", **key);
+            }
+        }
+        Ok(())
+    }
+
     fn mark(&self, mut roots: Vec<Handle>) {
         #[cfg(feature = "log_gc")]
         {
@@ -282,13 +363,14 @@ impl Heap {
     }
 
     fn sweep_at_capacity(&mut self) {
+        self.string_pool.sweep();
         let mut handles = Vec::with_capacity(self.handles.capacity() * 2);
         for handle in self.handles.iter_mut() {
             if handle.is_marked() {
                 handle.mark(false);
                 handles.push(*handle);
             } else {
-                as_gc!(handle, free());
+                self.byte_count -= as_gc!(handle, free());
             }
         }
         self.handles = handles;
@@ -333,6 +415,16 @@ impl Heap {
             println!("Done sweeping");
         }
     }
+
+    // give back capacity after a peak, without going below the initial capacity
+    fn shrink(&mut self) {
+        if self.handles.len() * 4 < self.handles.capacity()
+            && self.handles.capacity() > INITIAL_CAPACITY
+        {
+            self.handles
+                .shrink_to(INITIAL_CAPACITY.max(self.handles.len() * 2));
+        }
+    }
 }
 
 impl Drop for Heap {
@@ -360,8 +452,8 @@ mod tests {
         heap.intern_copy("");
     }
 
-    fn first(_args: &[Value]) -> Result<Value, String> {
-        if _args.len() > 0 {
+    fn first(_heap: &mut Heap, _args: &[Value]) -> Result<Value, String> {
+        if !_args.is_empty() {
             Ok(_args[0])
         } else {
             err!("Too few arguments.")
@@ -371,6 +463,91 @@ mod tests {
     #[test]
     fn store_native_function() {
         let mut heap = Heap::new();
-        heap.store(Native(first));
+        let name = heap.intern_copy("first");
+        heap.store(Native::new(name, first, None));
+    }
+
+    #[test]
+    fn validate() {
+        let mut heap = Heap::new();
+        let mut other = Heap::new();
+        let name = heap.intern_copy("A");
+        let class = heap.store(Class::new(name));
+        heap.store(Instance::new(class));
+        assert_eq!(heap.validate(), Ok(()));
+        let stray = other.store(Class::new(name));
+        heap.store(Instance::new(stray));
+        let msg = heap.validate().unwrap_err();
+        assert!(msg.starts_with("Instance at"), "{}", msg);
+        assert!(msg.ends_with("which is not in the heap."), "{}", msg);
+    }
+
+    #[test]
+    fn shrink_after_peak() {
+        let mut heap = Heap::new();
+        let keep = heap.intern_copy("keep");
+        for i in 0..5 * INITIAL_CAPACITY {
+            heap.intern_copy(&i.to_string());
+        }
+        assert!(heap.handles.capacity() >= 5 * INITIAL_CAPACITY);
+        heap.retain(vec![Handle::from(keep)]);
+        assert_eq!(heap.handles.len(), 1);
+        assert_eq!(heap.handles.capacity(), INITIAL_CAPACITY);
+        assert_eq!(heap.intern_copy("keep"), keep);
+    }
+
+    #[test]
+    fn sweep_at_capacity() {
+        let mut heap = Heap::new();
+        let keep = heap.intern_copy("keep");
+        let bytes = heap.byte_count();
+        for i in 1..INITIAL_CAPACITY {
+            heap.intern_copy(&i.to_string());
+        }
+        assert_eq!(heap.handles.len(), heap.handles.capacity());
+        heap.retain(vec![Handle::from(keep)]);
+        assert_eq!(heap.handles.len(), 1);
+        assert_eq!(heap.byte_count(), bytes);
+        assert_eq!(heap.intern_count(), 1);
+        assert_eq!(heap.validate(), Ok(()));
+    }
+
+    #[test]
+    fn threshold_and_growth() {
+        let mut heap = Heap::with_threshold(100);
+        heap.set_growth_factor(1.5);
+        let keep = heap.intern_copy("keep");
+        assert!(!heap.needs_gc());
+        for i in 0..10 {
+            heap.intern_copy(&i.to_string());
+        }
+        assert!(heap.needs_gc());
+        heap.retain(vec![Handle::from(keep)]);
+        assert_eq!(heap.next_gc(), 150);
+        assert!(!heap.needs_gc());
+
+        heap.set_growth_factor(0.5);
+        heap.retain(vec![Handle::from(keep)]);
+        assert_eq!(heap.next_gc(), 150);
+    }
+
+    #[test]
+    fn interning_is_order_independent() {
+        let words = ["init", "this", "super", "a", "b", "ab", "ba"];
+        let mut forward = Heap::new();
+        let mut backward = Heap::new();
+        let forward_handles: Vec<GC<Loxtr>> =
+            words.iter().map(|w| forward.intern_copy(w)).collect();
+        let backward_handles: Vec<GC<Loxtr>> = words
+            .iter()
+            .rev()
+            .map(|w| backward.intern_copy(w))
+            .rev()
+            .collect();
+        for ((word, a), b) in words.iter().zip(forward_handles).zip(backward_handles) {
+            assert_eq!(a.hash_code(), b.hash_code());
+            assert_eq!(forward.intern_copy(word), a);
+            assert_eq!(backward.intern_copy(word), b);
+        }
     }
 }
@@ -1,11 +1,14 @@
 // run time data structures
 
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    hash::{Hash, Hasher},
+};
 
 use crate::{
     chunk::Chunk,
     loxtr::Loxtr,
-    memory::{Handle, Kind, Traceable, GC},
+    memory::{Handle, Heap, Kind, Traceable, GC},
     table::Table,
 };
 
@@ -16,6 +19,9 @@ pub enum Value {
     False,
     Number(f64),
     Object(Handle),
+    // a global declared without initializer in strict mode, never seen by
+    // scripts
+    Undefined,
 }
 
 impl From<bool> for Value {
@@ -34,6 +40,90 @@ impl From<f64> for Value {
     }
 }
 
+impl TryFrom<Value> for f64 {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        if let Value::Number(number) = value {
+            Ok(number)
+        } else {
+            Err(format!("Expected a number but got '{}'.", value))
+        }
+    }
+}
+
+// conversions for embedding, which intern strings on the way in
+pub trait IntoValue {
+    fn into_value(self, heap: &mut Heap) -> Value;
+}
+
+pub trait FromValue: Sized {
+    fn from_value(value: Value, heap: &Heap) -> Result<Self, String>;
+}
+
+impl IntoValue for f64 {
+    fn into_value(self, _heap: &mut Heap) -> Value {
+        Value::from(self)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self, _heap: &mut Heap) -> Value {
+        Value::from(self)
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self, heap: &mut Heap) -> Value {
+        Value::from(heap.intern(self))
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self, heap: &mut Heap) -> Value {
+        Value::from(heap.intern_copy(self))
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self, _heap: &mut Heap) -> Value {
+        Value::Nil
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: Value, _heap: &Heap) -> Result<Self, String> {
+        f64::try_from(value)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value, _heap: &Heap) -> Result<Self, String> {
+        match value {
+            Value::True => Ok(true),
+            Value::False => Ok(false),
+            _ => err!("Expected a boolean but got '{}'.", value),
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: Value, _heap: &Heap) -> Result<Self, String> {
+        Loxtr::nullable(value)
+            .map(|string| String::from(string.as_ref()))
+            .ok_or_else(|| format!("Expected a string but got '{}'.", value))
+    }
+}
+
+impl FromValue for () {
+    fn from_value(value: Value, _heap: &Heap) -> Result<Self, String> {
+        match value {
+            Value::Nil => Ok(()),
+            _ => err!("Expected nil but got '{}'.", value),
+        }
+    }
+}
+
 impl<T: Traceable> From<GC<T>> for Value {
     fn from(value: GC<T>) -> Self {
         Value::Object(Handle::from(value))
@@ -44,6 +134,47 @@ impl Value {
     pub fn is_falsey(&self) -> bool {
         matches!(self, Value::Nil | Value::False)
     }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Undefined => "undefined",
+            Value::True | Value::False => "boolean",
+            Value::Number(_) => "number",
+            Value::Object(handle) => match handle.kind() {
+                Kind::Class => "class",
+                Kind::BoundMethod | Kind::Closure | Kind::Function | Kind::Native => "function",
+                Kind::Coroutine => "coroutine",
+                Kind::Instance => "instance",
+                Kind::List => "list",
+                Kind::String => "string",
+                Kind::Upvalue => "upvalue",
+            },
+        }
+    }
+}
+
+// consistent with the derived equality: strings are interned, so comparing and
+// hashing handles works for them, while instances and other objects hash by
+// identity. The zeroes are equal, so -0.0 is hashed as 0.0.
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Nil => state.write_u8(0),
+            Value::True => state.write_u8(1),
+            Value::False => state.write_u8(2),
+            Value::Number(a) => {
+                state.write_u8(3);
+                let a = if *a == 0.0 { 0.0 } else { *a };
+                state.write_u64(a.to_bits());
+            }
+            Value::Object(handle) => {
+                state.write_u8(4);
+                handle.hash(state);
+            }
+            Value::Undefined => state.write_u8(5),
+        }
+    }
 }
 
 impl Display for Value {
@@ -54,6 +185,17 @@ impl Display for Value {
             Value::Number(a) => a.fmt(f),
             Value::Object(a) => a.fmt(f),
             Value::True => write!(f, "true"),
+            Value::Undefined => write!(f, "undefined"),
+        }
+    }
+}
+
+impl Value {
+    // like display, but with strings quoted and escaped
+    pub fn to_repr(&self) -> String {
+        match Loxtr::nullable(*self) {
+            Some(str) => format!("{:?}", str.as_ref()),
+            None => self.to_string(),
         }
     }
 }
@@ -70,8 +212,20 @@ impl Traceable for Loxtr {
 pub struct Function {
     pub name: Option<GC<Loxtr>>,
     pub arity: u8,
+    // in order, for tools that refer to parameters by name
+    pub param_names: Vec<GC<Loxtr>>,
+    // where calls start that pass 0, 1, 2... of the parameters with defaults,
+    // empty without defaults
+    pub entry_points: Vec<usize>,
     pub upvalue_count: u8,
+    // calls return a coroutine instead of running the body
+    pub is_generator: bool,
+    // runs on property access, without parameter list
+    pub is_getter: bool,
     pub chunk: Chunk,
+    // name, slot and scope of each local variable
+    #[cfg(feature = "debug_info")]
+    pub locals: Vec<(Box<str>, u8, std::ops::Range<usize>)>,
 }
 
 impl Function {
@@ -79,18 +233,45 @@ impl Function {
         Self {
             name,
             arity: 0,
+            param_names: Vec::new(),
+            entry_points: Vec::new(),
             upvalue_count: 0,
+            is_generator: false,
+            is_getter: false,
             chunk: Chunk::new(),
+            #[cfg(feature = "debug_info")]
+            locals: Vec::new(),
         }
     }
+
+    pub fn min_arity(&self) -> u8 {
+        self.arity - self.entry_points.len().saturating_sub(1) as u8
+    }
+
+    // the offset to start a call with this many arguments
+    pub fn entry_point(&self, arity: u8) -> Option<usize> {
+        if self.entry_points.is_empty() {
+            return (arity == self.arity).then_some(0);
+        }
+        let left_out = self.arity.checked_sub(arity)? as usize;
+        let count = self.entry_points.len();
+        self.entry_points
+            .get(count.checked_sub(left_out + 1)?)
+            .copied()
+    }
 }
 
 impl Display for Function {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if let Some(str) = self.name {
-            write!(f, "<fn {}({}/{})>", *str, self.arity, self.upvalue_count)
-        } else {
-            write!(f, "<script>")
+        // the alternate form shows arity and upvalue count
+        match self.name {
+            Some(str) if f.alternate() => {
+                write!(f, "<fn {}({}/{})>", *str, self.arity, self.upvalue_count)
+            }
+            Some(str) => write!(f, "<fn {}>", *str),
+            None => {
+                write!(f, "<script>")
+            }
         }
     }
 }
@@ -99,18 +280,22 @@ impl Traceable for Function {
     const KIND: Kind = Kind::Function;
     // just consider initial allocation
     fn byte_count(&self) -> usize {
-        60 + self.chunk.byte_increment()
+        60 + 8 * (self.param_names.len() + self.entry_points.len()) + self.chunk.byte_increment()
     }
 
     fn trace(&self, collector: &mut Vec<Handle>) {
         if let Some(name) = self.name {
             collector.push(Handle::from(name))
         }
+        for &name in &self.param_names {
+            collector.push(Handle::from(name))
+        }
         for &value in &self.chunk.constants {
             if let Value::Object(h) = value {
                 collector.push(h)
             }
         }
+        self.chunk.trace_caches(collector);
     }
 }
 
@@ -118,6 +303,10 @@ pub struct Class {
     pub name: GC<Loxtr>,
     // heap allocated
     pub methods: Table<GC<Closure>>,
+    // called on the class itself, without receiver
+    pub statics: Table<GC<Closure>>,
+    // inherited members are looked up here instead of copied
+    pub super_class: Option<GC<Class>>,
 }
 
 impl Class {
@@ -125,22 +314,40 @@ impl Class {
         Self {
             name,
             methods: Table::new(),
+            statics: Table::new(),
+            super_class: None,
         }
     }
+
+    pub fn method(&self, name: GC<Loxtr>) -> Option<GC<Closure>> {
+        self.methods
+            .get(name)
+            .or_else(|| self.super_class?.method(name))
+    }
+
+    pub fn static_method(&self, name: GC<Loxtr>) -> Option<GC<Closure>> {
+        self.statics
+            .get(name)
+            .or_else(|| self.super_class?.static_method(name))
+    }
 }
 
 impl Traceable for Class {
     const KIND: Kind = Kind::Class;
 
     fn byte_count(&self) -> usize {
-        // 32 is 8 for name and 32 for Table
+        // 80 is 8 for name, 8 for super class and 32 for each Table
         // 16 is 8 for obj, 8 for closure
-        40 + 16 * self.methods.capacity()
+        80 + 16 * (self.methods.capacity() + self.statics.capacity())
     }
 
     fn trace(&self, collector: &mut Vec<Handle>) {
         collector.push(Handle::from(self.name));
+        if let Some(super_class) = self.super_class {
+            collector.push(Handle::from(super_class));
+        }
         self.methods.trace(collector);
+        self.statics.trace(collector);
     }
 }
 
@@ -214,6 +421,64 @@ impl Display for Closure {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CoroutineState {
+    Suspended,
+    Running,
+    Done,
+}
+
+// a suspended generator call: the slots of its frame and where to resume
+pub struct Coroutine {
+    pub closure: GC<Closure>,
+    pub ip: isize,
+    pub state: CoroutineState,
+    // heap allocated
+    pub stack: Vec<Value>,
+    // captured locals of a suspended generator, by offset into its stack
+    pub upvalues: Vec<(usize, GC<Upvalue>)>,
+}
+
+impl Coroutine {
+    pub fn new(closure: GC<Closure>, stack: &[Value]) -> Self {
+        Self {
+            closure,
+            ip: -1,
+            state: CoroutineState::Suspended,
+            stack: stack.to_vec(),
+            upvalues: Vec::new(),
+        }
+    }
+}
+
+impl Traceable for Coroutine {
+    const KIND: Kind = Kind::Coroutine;
+
+    fn byte_count(&self) -> usize {
+        72 + 16 * (self.stack.capacity() + self.upvalues.capacity())
+    }
+
+    fn trace(&self, collector: &mut Vec<Handle>) {
+        collector.push(Handle::from(self.closure));
+        for value in self.stack.iter() {
+            if let Value::Object(handle) = value {
+                collector.push(*handle);
+            }
+        }
+        for (_, upvalue) in self.upvalues.iter() {
+            collector.push(Handle::from(*upvalue));
+        }
+    }
+}
+
+impl Display for Coroutine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<generator ")?;
+        self.closure.fmt(f)?;
+        write!(f, ">")
+    }
+}
+
 pub struct Instance {
     pub class: GC<Class>,
     // heap allocated
@@ -276,12 +541,149 @@ impl Display for BoundMethod {
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct Native(pub fn(args: &[Value]) -> Result<Value, String>);
+pub struct List {
+    // heap allocated
+    pub items: Vec<Value>,
+}
+
+// nested lists deeper than this are elided, which also ends cycles
+const LIST_DISPLAY_DEPTH: usize = 8;
+
+impl List {
+    pub fn new(items: Vec<Value>) -> Self {
+        Self { items }
+    }
+
+    fn fmt_nested(&self, f: &mut std::fmt::Formatter<'_>, depth: usize) -> std::fmt::Result {
+        if depth >= LIST_DISPLAY_DEPTH {
+            return write!(f, "[...]");
+        }
+        write!(f, "[")?;
+        for (i, &item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match List::nullable(item) {
+                Some(list) => list.fmt_nested(f, depth + 1)?,
+                None => item.fmt(f)?,
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+impl Traceable for List {
+    const KIND: Kind = Kind::List;
+
+    fn byte_count(&self) -> usize {
+        24 + 16 * self.items.capacity()
+    }
+
+    fn trace(&self, collector: &mut Vec<Handle>) {
+        for value in self.items.iter() {
+            if let Value::Object(handle) = value {
+                collector.push(*handle);
+            }
+        }
+    }
+}
+
+impl Display for List {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_nested(f, 0)
+    }
+}
+
+pub type NativeFn = fn(heap: &mut Heap, args: &[Value]) -> Result<Value, String>;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ArgType {
+    Any,
+    Number,
+    String,
+    Instance,
+}
+
+impl ArgType {
+    fn accepts(self, value: Value) -> bool {
+        match self {
+            ArgType::Any => true,
+            ArgType::Number => matches!(value, Value::Number(_)),
+            ArgType::String => Loxtr::nullable(value).is_some(),
+            ArgType::Instance => Instance::nullable(value).is_some(),
+        }
+    }
+}
+
+impl Display for ArgType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgType::Any => write!(f, "a value"),
+            ArgType::Number => write!(f, "a number"),
+            ArgType::String => write!(f, "a string"),
+            ArgType::Instance => write!(f, "an instance"),
+        }
+    }
+}
+
+// natives the vm runs itself, because they use its input or output
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NativeIo {
+    Printf,
+    Read,
+    Write,
+}
+
+pub struct Native {
+    pub name: GC<Loxtr>,
+    pub function: NativeFn,
+    // checked before every call, none for natives that check their own
+    pub arg_types: Option<&'static [ArgType]>,
+    pub io: Option<NativeIo>,
+}
+
+impl Native {
+    pub fn new(name: GC<Loxtr>, function: NativeFn, arg_types: Option<&'static [ArgType]>) -> Self {
+        Self {
+            name,
+            function,
+            arg_types,
+            io: None,
+        }
+    }
+
+    pub fn check_args(&self, args: &[Value]) -> Result<(), String> {
+        let Some(arg_types) = self.arg_types else {
+            return Ok(());
+        };
+        if args.len() != arg_types.len() {
+            let plural = if arg_types.len() == 1 { "" } else { "s" };
+            return err!(
+                "{} expects {} argument{} but got {}.",
+                *self.name,
+                arg_types.len(),
+                plural,
+                args.len()
+            );
+        }
+        for (i, (arg_type, &arg)) in arg_types.iter().zip(args).enumerate() {
+            if !arg_type.accepts(arg) {
+                return err!(
+                    "{} expects {} for argument {}, got {}.",
+                    *self.name,
+                    arg_type,
+                    i + 1,
+                    arg.type_name()
+                );
+            }
+        }
+        Ok(())
+    }
+}
 
 impl std::fmt::Debug for Native {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "<native function>")
+        write!(f, "<native function {}>", *self.name)
     }
 }
 
@@ -289,10 +691,12 @@ impl Traceable for Native {
     const KIND: Kind = Kind::Native;
 
     fn byte_count(&self) -> usize {
-        8
+        40
     }
 
-    fn trace(&self, _collector: &mut Vec<Handle>) {}
+    fn trace(&self, collector: &mut Vec<Handle>) {
+        collector.push(Handle::from(self.name));
+    }
 }
 
 impl Display for Native {
@@ -300,3 +704,88 @@ impl Display for Native {
         write!(f, "<native>")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+
+    use crate::memory::Heap;
+
+    use super::*;
+
+    fn hash_of(value: Value) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn function_display() {
+        let mut heap = Heap::new();
+        let name = heap.intern_copy("f");
+        let mut function = Function::new(Some(name));
+        function.arity = 2;
+        function.upvalue_count = 1;
+        let function = Value::from(heap.store(function));
+        assert_eq!(format!("{}", function), "<fn f>");
+        assert_eq!(format!("{:#}", function), "<fn f(2/1)>");
+    }
+
+    fn round_trip<T: IntoValue + FromValue>(heap: &mut Heap, x: T) -> Result<T, String> {
+        let value = x.into_value(heap);
+        T::from_value(value, heap)
+    }
+
+    #[test]
+    fn host_conversions() {
+        let mut heap = Heap::new();
+        assert_eq!(round_trip(&mut heap, 1.5), Ok(1.5));
+        assert_eq!(round_trip(&mut heap, true), Ok(true));
+        assert_eq!(round_trip(&mut heap, false), Ok(false));
+        assert_eq!(round_trip(&mut heap, ()), Ok(()));
+        assert_eq!(
+            round_trip(&mut heap, String::from("a")),
+            Ok(String::from("a"))
+        );
+        let value = "b".into_value(&mut heap);
+        assert_eq!(value, Value::from(heap.intern_copy("b")));
+        assert_eq!(String::from_value(value, &heap), Ok(String::from("b")));
+
+        assert!(f64::from_value(Value::Nil, &heap).is_err());
+        assert!(bool::from_value(Value::from(0.0), &heap).is_err());
+        assert!(String::from_value(Value::True, &heap).is_err());
+        assert!(<()>::from_value(value, &heap).is_err());
+    }
+
+    #[test]
+    fn equal_numbers_hash_equally() {
+        assert_eq!(hash_of(Value::from(1.5)), hash_of(Value::from(3.0 / 2.0)));
+        assert_ne!(hash_of(Value::from(1.0)), hash_of(Value::from(2.0)));
+    }
+
+    #[test]
+    fn zeroes_hash_equally() {
+        assert_eq!(Value::from(0.0), Value::from(-0.0));
+        assert_eq!(hash_of(Value::from(0.0)), hash_of(Value::from(-0.0)));
+    }
+
+    #[test]
+    fn interned_strings_hash_equally() {
+        let mut heap = Heap::new();
+        let a = Value::from(heap.intern_copy("key"));
+        let b = Value::from(heap.intern_copy("key"));
+        assert_eq!(a, b);
+        assert_eq!(hash_of(a), hash_of(b));
+        assert_ne!(hash_of(Value::Nil), hash_of(Value::False));
+    }
+
+    #[test]
+    fn repr_quotes_strings() {
+        let mut heap = Heap::new();
+        let value = Value::from(heap.intern_copy("a\nb"));
+        assert_eq!(value.to_repr(), "\"a\\nb\"");
+        assert_eq!(Value::from(heap.intern_copy("nil")).to_repr(), "\"nil\"");
+        assert_eq!(Value::Nil.to_repr(), "nil");
+        assert_eq!(Value::from(1.5).to_repr(), "1.5");
+    }
+}
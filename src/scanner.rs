@@ -8,6 +8,8 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -15,12 +17,18 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    StarStar,
+    Percent,
+    Question,
+    Colon,
 
     // One or two character tokens.
     Bang,
     BangEqual,
+    DotDot,
     Equal,
     EqualEqual,
+    EqualEqualEqual,
     Greater,
     GreaterEqual,
     Less,
@@ -30,10 +38,14 @@ pub enum TokenType {
     Identifier,
     String,
     Number,
+    // a string up to an interpolated expression
+    Interpolation,
 
     // Keywords.
     And,
+    Case,
     Class,
+    Default,
     Else,
     False,
     Fun,
@@ -43,13 +55,20 @@ pub enum TokenType {
     Or,
     Print,
     Return,
+    Static,
     Super,
+    Switch,
     This,
     True,
     Var,
     While,
+    Yield,
 
     Error,
+    // a block comment without end
+    EndlessComment,
+    // a character that does not start any token
+    BadCharacter,
 
     End,
 }
@@ -83,11 +102,13 @@ pub struct Scanner<'src> {
     token_start: usize,
     token_line: u16,
     token_column: u16,
+    // open braces in each interpolated expression
+    interpolations: Vec<u16>,
 }
 
 impl<'src> Scanner<'src> {
     pub fn new(source: &'src str) -> Self {
-        Self {
+        let mut scanner = Self {
             source,
             current: 0,
             line: 1,
@@ -95,7 +116,13 @@ impl<'src> Scanner<'src> {
             token_start: 0,
             token_line: 1,
             token_column: 1,
+            interpolations: Vec::new(),
+        };
+        // skip a shebang line
+        if source.starts_with("#!") {
+            while !scanner.is_at_end() && !matches!(scanner.advance(), b'\n' | b'\r') {}
         }
+        scanner
     }
 
     fn is_at_end(&self) -> bool {
@@ -126,11 +153,13 @@ impl<'src> Scanner<'src> {
             return 0;
         }
         let ch = self.get_byte(self.current);
-        if ch == b'\n' {
+        // a lone '\r' ends a line too, '\r\n' counts at the '\n'
+        if ch == b'\n' || ch == b'\r' && self.peek_ahead() != b'\n' {
             self.line += 1;
             self.column = 1;
         } else if ch != b'\r' {
-            self.column += 1;
+            // generated code can have very long lines
+            self.column = self.column.saturating_add(1);
         }
         // for unicode
         loop {
@@ -163,7 +192,8 @@ impl<'src> Scanner<'src> {
         }
     }
 
-    fn skip_whitespace(&mut self) {
+    // false if a block comment runs to the end
+    fn skip_whitespace(&mut self) -> bool {
         loop {
             let ch = self.peek();
             if ch.is_ascii_whitespace() {
@@ -173,26 +203,60 @@ impl<'src> Scanner<'src> {
 
             // skip comments while we are at it
             if ch != b'/' {
-                return;
+                return true;
+            }
+            if self.peek_ahead() == b'*' {
+                if !self.block_comment() {
+                    return false;
+                }
+                continue;
             }
             if self.peek_ahead() != b'/' {
-                return;
+                return true;
             }
             self.current += 2;
             loop {
                 if self.is_at_end() {
-                    return;
+                    return true;
                 }
-                if self.advance() == b'\n' {
+                if matches!(self.advance(), b'\n' | b'\r') {
                     break;
                 }
             }
         }
     }
 
-    fn check_keyword(&self, word: &str, typ: TokenType) -> TokenType {
-        let start = self.current - word.len();
-        if self.source[start..self.current] == *word {
+    // block comments nest, an unterminated one becomes the next token
+    fn block_comment(&mut self) -> bool {
+        self.token_start = self.current;
+        self.token_line = self.line;
+        self.token_column = self.column;
+        let mut depth = 0;
+        loop {
+            if self.is_at_end() {
+                return false;
+            }
+            match self.advance() {
+                b'/' if self.peek() == b'*' => {
+                    self.advance();
+                    depth += 1;
+                }
+                b'*' if self.peek() == b'/' => {
+                    self.advance();
+                    depth -= 1;
+                    if depth == 0 {
+                        return true;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // word is the rest of the keyword after the bytes that selected it
+    fn check_keyword(&self, start: usize, word: &str, typ: TokenType) -> TokenType {
+        let start = self.token_start + start;
+        if self.current == start + word.len() && self.source[start..self.current] == *word {
             return typ;
         }
         TokenType::Identifier
@@ -201,40 +265,63 @@ impl<'src> Scanner<'src> {
     fn identifier_type(&self) -> TokenType {
         let start = self.get_byte(self.token_start);
         match start {
-            b'a' => self.check_keyword("nd", TokenType::And),
-            b'c' => self.check_keyword("lass", TokenType::Class),
-            b'e' => self.check_keyword("lse", TokenType::Else),
+            b'a' => self.check_keyword(1, "nd", TokenType::And),
+            b'c' => {
+                if self.current > self.token_start + 1 {
+                    match self.get_byte(self.token_start + 1) {
+                        b'a' => self.check_keyword(2, "se", TokenType::Case),
+                        b'l' => self.check_keyword(2, "ass", TokenType::Class),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
+            b'd' => self.check_keyword(1, "efault", TokenType::Default),
+            b'e' => self.check_keyword(1, "lse", TokenType::Else),
             b'f' => {
                 if self.current > self.token_start + 1 {
                     match self.get_byte(self.token_start + 1) {
-                        b'a' => self.check_keyword("lse", TokenType::False),
-                        b'o' => self.check_keyword("r", TokenType::For),
-                        b'u' => self.check_keyword("n", TokenType::Fun),
+                        b'a' => self.check_keyword(2, "lse", TokenType::False),
+                        b'o' => self.check_keyword(2, "r", TokenType::For),
+                        b'u' => self.check_keyword(2, "n", TokenType::Fun),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
+            b'i' => self.check_keyword(1, "f", TokenType::If),
+            b'n' => self.check_keyword(1, "il", TokenType::Nil),
+            b'o' => self.check_keyword(1, "r", TokenType::Or),
+            b'p' => self.check_keyword(1, "rint", TokenType::Print),
+            b'r' => self.check_keyword(1, "eturn", TokenType::Return),
+            b's' => {
+                if self.current > self.token_start + 1 {
+                    match self.get_byte(self.token_start + 1) {
+                        b't' => self.check_keyword(2, "atic", TokenType::Static),
+                        b'u' => self.check_keyword(2, "per", TokenType::Super),
+                        b'w' => self.check_keyword(2, "itch", TokenType::Switch),
                         _ => TokenType::Identifier,
                     }
                 } else {
                     TokenType::Identifier
                 }
             }
-            b'i' => self.check_keyword("f", TokenType::If),
-            b'n' => self.check_keyword("il", TokenType::Nil),
-            b'o' => self.check_keyword("r", TokenType::Or),
-            b'p' => self.check_keyword("rint", TokenType::Print),
-            b'r' => self.check_keyword("eturn", TokenType::Return),
-            b's' => self.check_keyword("uper", TokenType::Super),
             b't' => {
                 if self.current > self.token_start + 1 {
                     match self.get_byte(self.token_start + 1) {
-                        b'h' => self.check_keyword("is", TokenType::This),
-                        b'r' => self.check_keyword("ue", TokenType::True),
+                        b'h' => self.check_keyword(2, "is", TokenType::This),
+                        b'r' => self.check_keyword(2, "ue", TokenType::True),
                         _ => TokenType::Identifier,
                     }
                 } else {
                     TokenType::Identifier
                 }
             }
-            b'v' => self.check_keyword("ar", TokenType::Var),
-            b'w' => self.check_keyword("hile", TokenType::While),
+            b'v' => self.check_keyword(1, "ar", TokenType::Var),
+            b'w' => self.check_keyword(1, "hile", TokenType::While),
+            b'y' => self.check_keyword(1, "ield", TokenType::Yield),
             _ => TokenType::Identifier,
         }
     }
@@ -246,7 +333,15 @@ impl<'src> Scanner<'src> {
         self.token(self.identifier_type())
     }
 
+    // a malformed exponent or hex literal is left for the compiler to report
     fn number(&mut self) -> Token<'src> {
+        if self.lexeme() == "0" && matches!(self.peek(), b'x' | b'X') {
+            self.advance();
+            while self.peek().is_ascii_alphanumeric() {
+                self.advance();
+            }
+            return self.token(TokenType::Number);
+        }
         while self.peek().is_ascii_digit() {
             self.advance();
         }
@@ -256,22 +351,48 @@ impl<'src> Scanner<'src> {
                 self.advance();
             }
         }
+        if matches!(self.peek(), b'e' | b'E') {
+            self.advance();
+            if matches!(self.peek(), b'+' | b'-') {
+                self.advance();
+            }
+            while self.peek().is_ascii_digit() {
+                self.advance();
+            }
+        }
         self.token(TokenType::Number)
     }
 
+    // braces are doubled to appear literally, and an empty {} is literal
     fn string(&mut self) -> Token<'src> {
         loop {
             if self.is_at_end() {
                 return self.token(TokenType::Error);
             }
-            if self.advance() == b'"' {
-                return self.token(TokenType::String);
+            match self.advance() {
+                b'"' => return self.token(TokenType::String),
+                b'\\' => {
+                    self.advance();
+                }
+                b'{' if matches!(self.peek(), b'{' | b'}') => {
+                    self.advance();
+                }
+                b'{' => {
+                    self.interpolations.push(0);
+                    return self.token(TokenType::Interpolation);
+                }
+                b'}' if self.peek() == b'}' => {
+                    self.advance();
+                }
+                _ => (),
             }
         }
     }
 
     pub fn next(&mut self) -> Token<'src> {
-        self.skip_whitespace();
+        if !self.skip_whitespace() {
+            return self.token(TokenType::EndlessComment);
+        }
         self.token_start = self.current;
         self.token_line = self.line;
         self.token_column = self.column;
@@ -288,15 +409,49 @@ impl<'src> Scanner<'src> {
         match ch {
             b'(' => self.token(TokenType::LeftParen),
             b')' => self.token(TokenType::RightParen),
-            b'{' => self.token(TokenType::LeftBrace),
-            b'}' => self.token(TokenType::RightBrace),
+            b'[' => self.token(TokenType::LeftBracket),
+            b']' => self.token(TokenType::RightBracket),
+            b'{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.token(TokenType::LeftBrace)
+            }
+            b'}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string()
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.token(TokenType::RightBrace)
+                }
+                None => self.token(TokenType::RightBrace),
+            },
             b';' => self.token(TokenType::Semicolon),
             b',' => self.token(TokenType::Comma),
-            b'.' => self.token(TokenType::Dot),
+            b'.' => {
+                if self.peek() == b'.' {
+                    self.advance();
+                    self.token(TokenType::DotDot)
+                } else {
+                    self.token(TokenType::Dot)
+                }
+            }
             b'-' => self.token(TokenType::Minus),
             b'+' => self.token(TokenType::Plus),
             b'/' => self.token(TokenType::Slash),
-            b'*' => self.token(TokenType::Star),
+            b'*' => {
+                if self.peek() == b'*' {
+                    self.current += 1;
+                    self.token(TokenType::StarStar)
+                } else {
+                    self.token(TokenType::Star)
+                }
+            }
+            b'%' => self.token(TokenType::Percent),
+            b'?' => self.token(TokenType::Question),
+            b':' => self.token(TokenType::Colon),
             b'!' => {
                 if self.match_eq() {
                     self.token(TokenType::BangEqual)
@@ -305,10 +460,12 @@ impl<'src> Scanner<'src> {
                 }
             }
             b'=' => {
-                if self.match_eq() {
-                    self.token(TokenType::EqualEqual)
-                } else {
+                if !self.match_eq() {
                     self.token(TokenType::Equal)
+                } else if self.match_eq() {
+                    self.token(TokenType::EqualEqualEqual)
+                } else {
+                    self.token(TokenType::EqualEqual)
                 }
             }
             b'<' => {
@@ -326,7 +483,7 @@ impl<'src> Scanner<'src> {
                 }
             }
             b'"' => self.string(),
-            _ => self.token(TokenType::Error),
+            _ => self.token(TokenType::BadCharacter),
         }
     }
 }
@@ -417,6 +574,190 @@ mod tests {
         );
     }
 
+    #[test]
+    fn skip_shebang() {
+        let mut scanner = Scanner::new("#!/bin/rlox\nprint 1;");
+        assert_eq!(
+            scanner.next(),
+            Token {
+                token_type: TokenType::Print,
+                lexeme: "print",
+                line: 2,
+                column: 1
+            }
+        );
+        let mut scanner = Scanner::new("print 1; #!");
+        assert_eq!(scanner.next().token_type, TokenType::Print);
+    }
+
+    #[test]
+    fn keywords_match_whole_identifiers() {
+        let mut scanner =
+            Scanner::new("a outer elif fals or if false this t case cases switch s static st");
+        let types = [
+            TokenType::Identifier,
+            TokenType::Identifier,
+            TokenType::Identifier,
+            TokenType::Identifier,
+            TokenType::Or,
+            TokenType::If,
+            TokenType::False,
+            TokenType::This,
+            TokenType::Identifier,
+            TokenType::Case,
+            TokenType::Identifier,
+            TokenType::Switch,
+            TokenType::Identifier,
+            TokenType::Static,
+            TokenType::Identifier,
+            TokenType::End,
+        ];
+        for token_type in types {
+            assert_eq!(scanner.next().token_type, token_type);
+        }
+    }
+
+    #[test]
+    fn block_comments() {
+        let mut scanner = Scanner::new("/* a /* b */\n c */ x /**/ y");
+        assert_eq!(
+            scanner.next(),
+            Token {
+                token_type: TokenType::Identifier,
+                lexeme: "x",
+                line: 2,
+                column: 7
+            }
+        );
+        assert_eq!(scanner.next().lexeme, "y");
+        assert_eq!(scanner.next().token_type, TokenType::End);
+
+        let mut scanner = Scanner::new("x /* a /* b */ c");
+        assert_eq!(scanner.next().lexeme, "x");
+        assert_eq!(
+            scanner.next(),
+            Token {
+                token_type: TokenType::EndlessComment,
+                lexeme: "/* a /* b */ c",
+                line: 1,
+                column: 3
+            }
+        );
+        assert_eq!(scanner.next().token_type, TokenType::End);
+    }
+
+    #[test]
+    fn interpolation() {
+        let mut scanner = Scanner::new("\"x={x}, {{y}} {}\" }");
+        let tokens = [
+            (TokenType::Interpolation, "\"x={"),
+            (TokenType::Identifier, "x"),
+            (TokenType::String, "}, {{y}} {}\""),
+            (TokenType::RightBrace, "}"),
+            (TokenType::End, ""),
+        ];
+        for (token_type, lexeme) in tokens {
+            let token = scanner.next();
+            assert_eq!((token.token_type, token.lexeme), (token_type, lexeme));
+        }
+        let mut scanner = Scanner::new("\"a{ \"b{c}\" + {} }d\"");
+        let tokens = [
+            (TokenType::Interpolation, "\"a{"),
+            (TokenType::Interpolation, "\"b{"),
+            (TokenType::Identifier, "c"),
+            (TokenType::String, "}\""),
+            (TokenType::Plus, "+"),
+            (TokenType::LeftBrace, "{"),
+            (TokenType::RightBrace, "}"),
+            (TokenType::String, "}d\""),
+            (TokenType::End, ""),
+        ];
+        for (token_type, lexeme) in tokens {
+            let token = scanner.next();
+            assert_eq!((token.token_type, token.lexeme), (token_type, lexeme));
+        }
+    }
+
+    #[test]
+    fn bad_characters() {
+        let mut scanner = Scanner::new("1 @ é");
+        scanner.next();
+        for lexeme in ["@", "é"] {
+            let token = scanner.next();
+            assert_eq!(
+                (token.token_type, token.lexeme),
+                (TokenType::BadCharacter, lexeme)
+            );
+        }
+        assert_eq!(scanner.next().token_type, TokenType::End);
+    }
+
+    #[test]
+    fn equals_signs() {
+        let mut scanner = Scanner::new("= == === ====");
+        let types = [
+            TokenType::Equal,
+            TokenType::EqualEqual,
+            TokenType::EqualEqualEqual,
+            TokenType::EqualEqualEqual,
+            TokenType::Equal,
+            TokenType::End,
+        ];
+        for token_type in types {
+            assert_eq!(scanner.next().token_type, token_type);
+        }
+    }
+
+    #[test]
+    fn carriage_returns() {
+        for (source, lines) in [
+            ("a\rb\r\rc // d\re", [1, 2, 4, 5]),
+            ("a\r\nb\r\n\r\nc // d\r\ne", [1, 2, 4, 5]),
+        ] {
+            let mut scanner = Scanner::new(source);
+            for line in lines {
+                let token = scanner.next();
+                assert_eq!((token.line, token.column), (line, 1), "{:?}", source);
+            }
+            assert_eq!(scanner.next().token_type, TokenType::End);
+        }
+    }
+
+    #[test]
+    fn stars() {
+        let mut scanner = Scanner::new("* ** *** /**/*");
+        let types = [
+            TokenType::Star,
+            TokenType::StarStar,
+            TokenType::StarStar,
+            TokenType::Star,
+            TokenType::Star,
+            TokenType::End,
+        ];
+        for token_type in types {
+            assert_eq!(scanner.next().token_type, token_type);
+        }
+    }
+
+    #[test]
+    fn number_forms() {
+        let mut scanner = Scanner::new("1e10 1.5E-3 2e+2 0xFF 0x 0xg 1e 1.e3");
+        let lexemes = [
+            "1e10", "1.5E-3", "2e+2", "0xFF", "0x", "0xg", "1e", "1", ".", "e3",
+        ];
+        for lexeme in lexemes {
+            assert_eq!(scanner.next().lexeme, lexeme);
+        }
+        assert_eq!(scanner.next().token_type, TokenType::End);
+    }
+
+    #[test]
+    fn escaped_quotes() {
+        let mut scanner = Scanner::new(r#""a\"b\\" "c\"#);
+        assert_eq!(scanner.next().lexeme, r#""a\"b\\""#);
+        assert_eq!(scanner.next().token_type, TokenType::Error);
+    }
+
     #[test]
     fn block_one_plus_two() {
         let mut scanner = Scanner::new(
@@ -23,6 +23,12 @@ pub struct Table<V: Clone> {
     entries: Box<[Entry<V>]>,
 }
 
+impl<V: Clone> Default for Table<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<V: Clone> Table<V> {
     const MAX_LOAD: f64 = 0.75;
     pub fn new() -> Self {
@@ -108,6 +114,31 @@ impl<V: Clone> Table<V> {
         key_existed
     }
 
+    pub fn keys(&self) -> impl Iterator<Item = &GC<Loxtr>> {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::Taken { key, value: _ } => Some(key),
+            _ => None,
+        })
+    }
+
+    // keeps the capacity
+    pub fn clear(&mut self) {
+        self.count = 0;
+        for entry in self.entries.iter_mut() {
+            *entry = Entry::Empty;
+        }
+    }
+
+    pub fn retain(&mut self, f: impl Fn(GC<Loxtr>, &V) -> bool) {
+        for entry in self.entries.iter_mut() {
+            if let Entry::Taken { key, value } = entry {
+                if !f(*key, value) {
+                    *entry = Entry::Tombstone;
+                }
+            }
+        }
+    }
+
     pub fn set_all(&mut self, other: &Table<V>) {
         if self.capacity < other.capacity {
             self.grow(other.capacity)
@@ -123,7 +154,8 @@ impl<V: Clone> Table<V> {
 impl Table<GC<Closure>> {
     pub fn trace(&self, collector: &mut Vec<Handle>) {
         for entry in self.entries.iter() {
-            if let Entry::Taken { key: _, value } = entry {
+            if let Entry::Taken { key, value } = entry {
+                collector.push(Handle::from(*key));
                 collector.push(Handle::from(*value))
             }
         }
@@ -132,12 +164,11 @@ impl Table<GC<Closure>> {
 impl Table<Value> {
     pub fn trace(&self, collector: &mut Vec<Handle>) {
         for entry in self.entries.iter() {
-            if let Entry::Taken {
-                key: _,
-                value: Value::Object(handle),
-            } = entry
-            {
-                collector.push(*handle)
+            if let Entry::Taken { key, value } = entry {
+                collector.push(Handle::from(*key));
+                if let Value::Object(handle) = value {
+                    collector.push(*handle)
+                }
             }
         }
     }
@@ -189,9 +220,57 @@ mod tests {
         let key = heap.intern_copy("name");
         let handle = Handle::from(key);
         assert_eq!(handle.kind(), Kind::String);
-        assert_eq!(key.is_marked(), false);
+        assert!(!key.is_marked());
         assert_eq!(key.as_ref(), "name");
         assert!(table.set(key, ()));
         assert!(table.get(key).is_some());
     }
+
+    #[test]
+    pub fn clear() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let keys: Vec<GC<Loxtr>> = ["a", "b", "c"].map(|k| heap.intern_copy(k)).to_vec();
+        for (i, &key) in keys.iter().enumerate() {
+            table.set(key, Value::from(i as f64));
+        }
+        let capacity = table.capacity();
+        table.clear();
+        assert_eq!(table.capacity(), capacity);
+        assert_eq!(table.keys().count(), 0);
+        for &key in keys.iter() {
+            assert!(table.get(key).is_none());
+        }
+        assert!(table.set(keys[0], Value::Nil));
+        assert_eq!(table.get(keys[0]), Some(Value::Nil));
+    }
+
+    #[test]
+    pub fn retain() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        for name in ["apple", "avocado", "banana", "cherry"] {
+            table.set(heap.intern_copy(name), Value::Nil);
+        }
+        table.retain(|key, _| key.as_ref().starts_with('a'));
+        let mut keys: Vec<&str> = table.keys().map(|key| key.as_ref()).collect();
+        keys.sort();
+        assert_eq!(keys, ["apple", "avocado"]);
+        assert!(table.get(heap.intern_copy("banana")).is_none());
+        assert!(table.get(heap.intern_copy("apple")).is_some());
+    }
+
+    #[test]
+    pub fn keys_survive_collection() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        table.set(heap.intern(String::from("field")), Value::Nil);
+        let mut roots = Vec::new();
+        table.trace(&mut roots);
+        heap.retain(roots);
+        assert_eq!(heap.validate(), Ok(()));
+        assert!(heap.interned_strings().any(|name| name == "field"));
+        let key = table.keys().next().copied().unwrap();
+        assert_eq!(key.as_ref(), "field");
+    }
 }
@@ -1,31 +1,275 @@
-use std::time;
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    io::{self, BufRead, Write},
+    time::{self, Instant},
+};
 
 use crate::{
-    chunk::{Chunk, Op},
+    chunk::{Chunk, Op, OP_COUNT},
     common::U8_COUNT,
-    compiler::compile,
+    compiler::{compile, compile_eval, compile_strict},
     loxtr::Loxtr,
     memory::{Handle, Heap, Kind, Traceable, GC},
-    object::{BoundMethod, Class, Closure, Instance, Native, Upvalue, Value},
+    object::{
+        ArgType, BoundMethod, Class, Closure, Coroutine, CoroutineState, Function, Instance, List,
+        Native, NativeFn, NativeIo, Upvalue, Value,
+    },
     table::Table,
 };
 
-const MAX_FRAMES: usize = 0x40;
-const STACK_SIZE: usize = MAX_FRAMES * U8_COUNT;
+// each frame can use up to U8_COUNT slots of the value stack
+const MAX_FRAMES: usize = 0x400;
+const RECURSION_HINT_DEPTH: usize = 0x10;
+
+// levenshtein distance, counted in chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, x) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &y) in b.iter().enumerate() {
+            let next = (diagonal + (x != y) as usize)
+                .min(row[j] + 1)
+                .min(row[j + 1] + 1);
+            diagonal = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
 
-fn clock_native(_args: &[Value]) -> Result<Value, String> {
+fn clock_native(_heap: &mut Heap, _args: &[Value]) -> Result<Value, String> {
     match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
         Ok(duration) => Ok(Value::from(duration.as_secs_f64())),
         Err(x) => Err(x.to_string()),
     }
 }
 
-const CLOCK_NATIVE: Native = Native(clock_native);
+// the arity and types are checked against the arg types of the native
+fn numbers<const N: usize>(args: &[Value]) -> [f64; N] {
+    let mut numbers = [0.0; N];
+    for (number, &arg) in numbers.iter_mut().zip(args) {
+        if let Value::Number(x) = arg {
+            *number = x;
+        }
+    }
+    numbers
+}
+
+fn sqrt_native(_heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let [x] = numbers(args);
+    Ok(Value::from(x.sqrt()))
+}
+
+fn floor_native(_heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let [x] = numbers(args);
+    Ok(Value::from(x.floor()))
+}
+
+fn ceil_native(_heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let [x] = numbers(args);
+    Ok(Value::from(x.ceil()))
+}
+
+fn abs_native(_heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let [x] = numbers(args);
+    Ok(Value::from(x.abs()))
+}
+
+fn pow_native(_heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let [x, y] = numbers(args);
+    Ok(Value::from(x.powf(y)))
+}
+
+fn min_native(_heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let [x, y] = numbers(args);
+    Ok(Value::from(x.min(y)))
+}
+
+fn max_native(_heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let [x, y] = numbers(args);
+    Ok(Value::from(x.max(y)))
+}
+
+fn class_of_native(_heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let instance: GC<Instance> = GC::from(args[0]);
+    Ok(Value::from(instance.class))
+}
+
+fn list_index(list: &List, index: Value) -> Result<usize, String> {
+    let Value::Number(number) = index else {
+        return err!("List index must be a number, not '{}'.", index);
+    };
+    if number.fract() != 0.0 || number < 0.0 || number >= list.items.len() as f64 {
+        return err!(
+            "List index {} is out of range for length {}.",
+            number,
+            list.items.len()
+        );
+    }
+    Ok(number as usize)
+}
+
+// strings have a fixed set of members, counted and sliced by characters
+fn string_property(string: GC<Loxtr>, name: GC<Loxtr>) -> Result<Value, String> {
+    match name.as_ref() {
+        "length" => Ok(Value::from(string.as_ref().chars().count() as f64)),
+        _ => err!("Undefined property '{}' on string.", *name),
+    }
+}
+
+fn string_method(
+    heap: &mut Heap,
+    string: GC<Loxtr>,
+    name: GC<Loxtr>,
+    args: &[Value],
+) -> Result<Value, String> {
+    let str = string.as_ref();
+    match (name.as_ref(), args) {
+        ("toUpper", []) => Ok(Value::from(heap.intern(str.to_uppercase()))),
+        ("toLower", []) => Ok(Value::from(heap.intern(str.to_lowercase()))),
+        ("substring", &[Value::Number(start), Value::Number(end)]) => {
+            let length = str.chars().count();
+            if start.fract() != 0.0
+                || end.fract() != 0.0
+                || start < 0.0
+                || start > end
+                || end > length as f64
+            {
+                return err!(
+                    "Substring {}..{} is out of range for length {}.",
+                    start,
+                    end,
+                    length
+                );
+            }
+            let substring = str
+                .chars()
+                .skip(start as usize)
+                .take((end - start) as usize)
+                .collect();
+            Ok(Value::from(heap.intern(substring)))
+        }
+        ("substring", [start, end]) => {
+            err!(
+                "Substring bounds must be numbers, not '{}' and '{}'.",
+                start,
+                end
+            )
+        }
+        ("toUpper" | "toLower" | "substring", _) => {
+            err!(
+                "Wrong number of arguments for string method '{}': {}.",
+                *name,
+                args.len()
+            )
+        }
+        _ => err!("Undefined method '{}' on string.", *name),
+    }
+}
+
+// instances of the same class with deep equal fields are deep equal, pairs
+// that are already being compared count as equal to cut cycles
+fn deep_equal(a: Value, b: Value) -> bool {
+    // no recursion, so long chains of instances cannot overflow the stack
+    let mut pending = vec![(a, b)];
+    let mut assumed = HashSet::new();
+    while let Some((a, b)) = pending.pop() {
+        if a == b {
+            continue;
+        }
+        let (Some(x), Some(y)) = (Instance::nullable(a), Instance::nullable(b)) else {
+            return false;
+        };
+        if x.class != y.class || x.properties.keys().count() != y.properties.keys().count() {
+            return false;
+        }
+        if !assumed.insert((Handle::from(x), Handle::from(y))) {
+            continue;
+        }
+        for &key in x.properties.keys() {
+            match (x.properties.get(key), y.properties.get(key)) {
+                (Some(v), Some(w)) => pending.push((v, w)),
+                _ => return false,
+            }
+        }
+    }
+    true
+}
+
+fn name_of_native(_heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let value = args[0];
+    let name = if let Some(class) = Class::nullable(value) {
+        Some(class.name)
+    } else if let Some(closure) = Closure::nullable(value) {
+        closure.function.name
+    } else if let Some(bm) = BoundMethod::nullable(value) {
+        bm.method.function.name
+    } else {
+        return err!("Only classes and functions have names, not '{}'.", value);
+    };
+    Ok(name.map_or(Value::Nil, Value::from))
+}
+
+// true for fields set to nil, false for methods
+fn has_property_native(_heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let instance: GC<Instance> = GC::from(args[0]);
+    Ok(Value::from(
+        instance.properties.get(GC::from(args[1])).is_some(),
+    ))
+}
+
+// substitute the arguments for the {} in the format string, {{ and }} for
+// single braces, other braces stay
+fn format(args: &[Value]) -> Result<String, String> {
+    let Some((&format, mut args)) = args.split_first() else {
+        return err!("Expected a format string.");
+    };
+    let format = Loxtr::nullable(format)
+        .ok_or_else(|| format!("Format must be a string, not '{}'.", format))?;
+    let mut text = String::new();
+    let mut chars = format.as_ref().chars();
+    while let Some(ch) = chars.next() {
+        match (ch, chars.clone().next()) {
+            ('{', Some('}')) => {
+                let Some((arg, rest)) = args.split_first() else {
+                    return err!("Too few arguments for '{}'.", *format);
+                };
+                text.push_str(&arg.to_string());
+                args = rest;
+                chars.next();
+            }
+            ('{', Some('{')) | ('}', Some('}')) => {
+                text.push(ch);
+                chars.next();
+            }
+            _ => text.push(ch),
+        }
+    }
+    if !args.is_empty() {
+        return err!("Too many arguments for '{}'.", *format);
+    }
+    Ok(text)
+}
+
+fn format_native(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::from(heap.intern(format(args)?)))
+}
+
+// stands in for the natives that the vm runs itself
+fn io_native(_heap: &mut Heap, _args: &[Value]) -> Result<Value, String> {
+    err!("Input and output natives need a vm.")
+}
 
 struct CallFrame {
     ip: isize,
     slots: usize,
     closure: GC<Closure>,
+    coroutine: Option<GC<Coroutine>>,
+    // the arity of a call to make with the result, for invoked getters
+    then_call: Option<u8>,
 }
 
 impl CallFrame {
@@ -34,6 +278,8 @@ impl CallFrame {
             ip: -1,
             slots,
             closure,
+            coroutine: None,
+            then_call: None,
         }
     }
     fn chunk(&self) -> &Chunk {
@@ -48,10 +294,23 @@ impl CallFrame {
         self.ip += self.chunk().read_short(self.ip as usize + 1) as isize;
     }
 
+    fn jump_forward_long(&mut self) {
+        self.ip += self.chunk().jump_distance(self.ip as usize) as isize;
+    }
+
     fn jump_back(&mut self) {
         self.ip -= self.chunk().read_short(self.ip as usize + 1) as isize;
     }
 
+    fn jump_back_long(&mut self) {
+        self.ip -= self.chunk().jump_distance(self.ip as usize) as isize;
+    }
+
+    fn read_short(&mut self) -> u16 {
+        self.ip += 2;
+        self.chunk().read_short(self.ip as usize - 1)
+    }
+
     fn read_constant(&mut self) -> Value {
         self.ip += 1;
         self.chunk().read_constant(self.ip as usize)
@@ -79,29 +338,110 @@ macro_rules! binary_op {
     }};
 }
 
+// names the vm dispatches on, interned once instead of on every lookup
+struct CommonStrings {
+    init: GC<Loxtr>,
+    str: GC<Loxtr>,
+    add: GC<Loxtr>,
+    iter: GC<Loxtr>,
+    next: GC<Loxtr>,
+}
+
+impl CommonStrings {
+    fn new(heap: &mut Heap) -> Self {
+        Self {
+            init: heap.intern_copy("init"),
+            str: heap.intern_copy("__str__"),
+            add: heap.intern_copy("__add__"),
+            iter: heap.intern_copy("iter"),
+            next: heap.intern_copy("next"),
+        }
+    }
+
+    fn trace(&self, collector: &mut Vec<Handle>) {
+        for name in [self.init, self.str, self.add, self.iter, self.next] {
+            collector.push(Handle::from(name));
+        }
+    }
+}
+
 pub struct VM {
-    values: [Value; STACK_SIZE],
+    // heap allocated, as its size depends on the frame limit
+    values: Box<[Value]>,
+    max_frames: usize,
     stack_top: usize,
     frames: Vec<CallFrame>,
     open_upvalues: Option<GC<Upvalue>>,
     globals: Table<Value>,
-    init_string: GC<Loxtr>,
+    // natives, which scripts can shadow but not assign to
+    builtins: Table<Value>,
+    common_strings: CommonStrings,
     heap: Heap,
+    output_enabled: bool,
+    function_details: bool,
+    truthiness: TruthinessMode,
+    // execution counts by op code, when profiling
+    op_counts: Option<Box<[u64; OP_COUNT]>>,
+    strict: bool,
+    // (function name, line) pairs
+    breakpoints: Vec<(Box<str>, u16)>,
+    on_breakpoint: Option<Box<BreakpointHandler>>,
+    output: Box<dyn Write>,
+    error_output: Box<dyn Write>,
+    input: Box<dyn BufRead>,
 }
 
+// receives the function name, the line and the slots of the current frame
+pub type BreakpointHandler = dyn FnMut(&str, u16, &[Value]);
+
 impl VM {
-    pub fn new(mut heap: Heap) -> Self {
-        let init_string = heap.intern_copy("init");
+    pub fn new(heap: Heap) -> Self {
+        Self::with_output(heap, Box::new(io::stdout()))
+    }
+
+    pub fn with_output(mut heap: Heap, output: Box<dyn Write>) -> Self {
+        let common_strings = CommonStrings::new(&mut heap);
         let mut s = Self {
-            values: [Value::Nil; STACK_SIZE],
+            values: vec![Value::Nil; MAX_FRAMES * U8_COUNT].into_boxed_slice(),
+            max_frames: MAX_FRAMES,
             stack_top: 0,
-            frames: Vec::with_capacity(MAX_FRAMES),
+            frames: Vec::new(),
             open_upvalues: None,
             globals: Table::new(),
-            init_string,
+            builtins: Table::new(),
+            common_strings,
             heap,
+            output_enabled: true,
+            function_details: false,
+            truthiness: TruthinessMode::Lox,
+            op_counts: None,
+            strict: false,
+            breakpoints: Vec::new(),
+            on_breakpoint: None,
+            output,
+            error_output: Box::new(io::stderr()),
+            input: Box::new(io::BufReader::new(io::stdin())),
         };
-        s.define_native("clock", CLOCK_NATIVE);
+        s.register_native("clock", clock_native, Some(&[]));
+        s.register_native("class_of", class_of_native, Some(&[ArgType::Instance]));
+        // classes, functions and bound methods have names
+        s.register_native("name_of", name_of_native, Some(&[ArgType::Any]));
+        s.register_native(
+            "has_property",
+            has_property_native,
+            Some(&[ArgType::Instance, ArgType::String]),
+        );
+        s.register_native("format", format_native, None);
+        s.register_native("sqrt", sqrt_native, Some(&[ArgType::Number]));
+        s.register_native("floor", floor_native, Some(&[ArgType::Number]));
+        s.register_native("ceil", ceil_native, Some(&[ArgType::Number]));
+        s.register_native("abs", abs_native, Some(&[ArgType::Number]));
+        s.register_native("pow", pow_native, Some(&[ArgType::Number; 2]));
+        s.register_native("min", min_native, Some(&[ArgType::Number; 2]));
+        s.register_native("max", max_native, Some(&[ArgType::Number; 2]));
+        s.register_io_native("printf", NativeIo::Printf, None);
+        s.register_io_native("read", NativeIo::Read, Some(&[]));
+        s.register_io_native("write", NativeIo::Write, Some(&[ArgType::Any]));
         s
     }
     pub fn capture_upvalue(&mut self, location: usize) -> GC<Upvalue> {
@@ -112,7 +452,8 @@ impl VM {
                 if location == index {
                     return link;
                 }
-                if location < index {
+                // sorted from the top of the stack down, like close_upvalues expects
+                if index < location {
                     break;
                 }
                 previous = current;
@@ -173,7 +514,10 @@ impl VM {
             println!("collect frames");
         }
         for frame in &self.frames {
-            collector.push(Handle::from(frame.closure))
+            collector.push(Handle::from(frame.closure));
+            if let Some(coroutine) = frame.coroutine {
+                collector.push(Handle::from(coroutine));
+            }
         }
         #[cfg(feature = "log_gc")]
         {
@@ -187,23 +531,242 @@ impl VM {
             println!("collect globals");
         }
         self.globals.trace(&mut collector);
+        self.builtins.trace(&mut collector);
         // no compiler roots
         #[cfg(feature = "log_gc")]
         {
-            println!("collect init string");
+            println!("collect common strings");
         }
-        collector.push(Handle::from(self.init_string));
+        self.common_strings.trace(&mut collector);
         collector
     }
 
-    fn define_native(&mut self, name: &str, native_fn: Native) {
+    // print statements still evaluate their operand when disabled
+    pub fn set_output_enabled(&mut self, enabled: bool) {
+        self.output_enabled = enabled;
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    // read lines come from here
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = input;
+    }
+
+    // make output visible that doesn't end in a newline yet
+    pub fn flush_output(&mut self) -> Result<(), String> {
+        self.output.flush().map_err(|e| e.to_string())
+    }
+
+    // deeper calls are a stack overflow, the value stack grows along
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        let max_frames = max_frames.max(1);
+        let mut values = vec![Value::Nil; max_frames * U8_COUNT];
+        let kept = self.stack_top.min(values.len());
+        values[..kept].copy_from_slice(&self.values[..kept]);
+        self.values = values.into_boxed_slice();
+        self.max_frames = max_frames;
+    }
+
+    // runtime errors are written here as well as returned
+    pub fn set_error_output(&mut self, error_output: Box<dyn Write>) {
+        self.error_output = error_output;
+    }
+
+    // show arity and upvalue count of functions in output and stack traces
+    pub fn set_function_details(&mut self, details: bool) {
+        self.function_details = details;
+    }
+
+    pub fn enable_profiling(&mut self) {
+        self.op_counts
+            .get_or_insert_with(|| Box::new([0; OP_COUNT]));
+    }
+
+    pub fn op_counts(&self) -> [u64; OP_COUNT] {
+        self.op_counts.as_deref().copied().unwrap_or([0; OP_COUNT])
+    }
+
+    pub fn set_truthiness(&mut self, truthiness: TruthinessMode) {
+        self.truthiness = truthiness;
+    }
+
+    fn is_falsey(&self, value: Value) -> bool {
+        match self.truthiness {
+            TruthinessMode::Lox => value.is_falsey(),
+            TruthinessMode::Loose => {
+                value.is_falsey()
+                    || value == Value::from(0.0)
+                    || Loxtr::nullable(value).is_some_and(|str| str.as_ref().is_empty())
+            }
+        }
+    }
+
+    fn show<T: Display>(&self, t: T) -> String {
+        if self.function_details {
+            format!("{:#}", t)
+        } else {
+            t.to_string()
+        }
+    }
+
+    pub fn intern_count(&self) -> usize {
+        self.heap.intern_count()
+    }
+
+    // forget all globals, which uncovers shadowed natives
+    pub fn reset_globals(&mut self) {
+        self.globals = Table::new();
+    }
+
+    pub fn collect_garbage(&mut self) {
+        let roots = self.roots();
+        self.heap.retain(roots);
+    }
+
+    // the number of objects and bytes on the heap
+    pub fn heap_stats(&self) -> (usize, usize) {
+        (self.heap.object_count(), self.heap.byte_count())
+    }
+
+    pub fn interned_strings(&self) -> impl Iterator<Item = &str> {
+        self.heap.interned_strings()
+    }
+
+    // report lints as warnings while compiling, and fail on reading globals
+    // that were declared without a value
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn add_breakpoint(&mut self, function_name: &str, line: u16) {
+        self.breakpoints.push((Box::from(function_name), line));
+    }
+
+    pub fn on_breakpoint(&mut self, handler: impl FnMut(&str, u16, &[Value]) + 'static) {
+        self.on_breakpoint = Some(Box::new(handler));
+    }
+
+    // fires when the current instruction is the first of a line with a breakpoint
+    fn check_breakpoint(&mut self) {
+        let frame = &self.frames[self.frames.len() - 1];
+        let ip = frame.ip as usize;
+        let lines = &frame.chunk().lines;
+        if ip > 0 && lines[ip - 1] == lines[ip] {
+            return;
+        }
+        let line = lines[ip];
+        let function = frame.closure.function;
+        let name = match &function.name {
+            Some(name) => name.as_ref(),
+            None => "script",
+        };
+        if !self
+            .breakpoints
+            .iter()
+            .any(|(n, l)| *l == line && n.as_ref() == name)
+        {
+            return;
+        }
+        if let Some(handler) = &mut self.on_breakpoint {
+            handler(name, line, &self.values[frame.slots..self.stack_top]);
+        }
+    }
+
+    // natives get the heap to allocate their results, and are kept by reset_globals
+    // as builtins
+    pub fn register_native(
+        &mut self,
+        name: &str,
+        function: NativeFn,
+        arg_types: Option<&'static [ArgType]>,
+    ) {
+        let key = self.heap.intern_copy(name);
+        self.push(Value::from(key));
+        let value = Value::from(self.new_obj(Native::new(key, function, arg_types)));
+        self.builtins.set(key, value);
+        self.pop();
+    }
+
+    fn register_io_native(
+        &mut self,
+        name: &str,
+        io: NativeIo,
+        arg_types: Option<&'static [ArgType]>,
+    ) {
         let key = self.heap.intern_copy(name);
         self.push(Value::from(key));
-        let value = Value::from(self.new_obj(native_fn));
-        self.globals.set(key, value);
+        let mut native = Native::new(key, io_native, arg_types);
+        native.io = Some(io);
+        let value = Value::from(self.new_obj(native));
+        self.builtins.set(key, value);
         self.pop();
     }
 
+    // read gives a line without its end, or nil at the end of the input, the
+    // arguments are on top of the stack
+    fn run_io_native(&mut self, io: NativeIo, arity: usize) -> Result<Value, String> {
+        match io {
+            NativeIo::Printf => {
+                // the format is checked even when output is disabled
+                let text = format(&self.values[self.stack_top - arity..self.stack_top])?;
+                self.write_output(&text, "\n")?;
+                Ok(Value::Nil)
+            }
+            NativeIo::Read => {
+                // a prompt written before must be visible while waiting
+                self.flush_output()?;
+                let mut line = String::new();
+                if self.input.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+                    return Ok(Value::Nil);
+                }
+                let line = line.trim_end_matches(['\n', '\r']);
+                Ok(Value::from(self.heap.intern_copy(line)))
+            }
+            NativeIo::Write => {
+                let text = self.show(self.peek(0));
+                self.write_output(&text, "")?;
+                Ok(Value::Nil)
+            }
+        }
+    }
+
+    // print statements and natives all write here, so their output stays in order
+    fn write_output(&mut self, text: &str, end: &str) -> Result<(), String> {
+        if self.output_enabled {
+            write!(self.output, "{}{}", text, end).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // the specialized additions fall back on this when their guess is wrong,
+    // so the errors are the same
+    fn add(&mut self) -> Result<(), String> {
+        if let &[a, b] = self.tail(2)? {
+            if let (Some(a), Some(b)) = (Loxtr::nullable(a), Loxtr::nullable(b)) {
+                let c = self.concatenate(a.as_ref(), b.as_ref());
+                self.stack_top -= 2;
+                self.push(c);
+                return Ok(());
+            }
+
+            if let (Value::Number(a), Value::Number(b)) = (a, b) {
+                self.stack_top -= 2;
+                self.push(Value::from(a + b));
+                return Ok(());
+            }
+
+            return err!(
+                "Operands must be either numbers or strings, found '{}' and '{}'",
+                a,
+                b
+            );
+        }
+        Ok(())
+    }
+
     fn push(&mut self, value: Value) {
         self.values[self.stack_top] = value;
         self.stack_top += 1;
@@ -219,22 +782,157 @@ impl VM {
     }
 
     fn call(&mut self, closure: GC<Closure>, arity: u8) -> Result<(), String> {
-        if arity != closure.function.arity {
-            return err!(
-                "Expected {} arguments but got {}.",
-                closure.function.arity,
-                arity
-            );
+        let function = closure.function;
+        let Some(entry_point) = function.entry_point(arity) else {
+            return if function.min_arity() == function.arity {
+                err!("Expected {} arguments but got {}.", function.arity, arity)
+            } else {
+                err!(
+                    "Expected {} to {} arguments but got {}.",
+                    function.min_arity(),
+                    function.arity,
+                    arity
+                )
+            };
+        };
+        // skip the defaults of parameters that have arguments
+        let ip = entry_point as isize - 1;
+
+        let slots = self.stack_top - arity as usize - 1;
+        if closure.function.is_generator {
+            let mut coroutine =
+                self.new_obj(Coroutine::new(closure, &self.values[slots..self.stack_top]));
+            coroutine.ip = ip;
+            self.stack_top = slots;
+            self.push(Value::from(coroutine));
+            return Ok(());
+        }
+
+        if self.frames.len() == self.max_frames {
+            return Err(self.stack_overflow());
+        }
+        let mut frame = CallFrame::new(slots, closure);
+        frame.ip = ip;
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    // a closure called in tail position takes over the current frame, so
+    // tail recursion runs in constant space
+    fn tail_call(&mut self, callee: Value, arity: u8) -> Result<(), String> {
+        let Some(closure) = Closure::nullable(callee) else {
+            return self.call_value(callee, arity);
+        };
+        let entry_point = closure.function.entry_point(arity);
+        if entry_point.is_none()
+            || closure.function.is_generator
+            || self.top_frame().coroutine.is_some()
+        {
+            return self.call(closure, arity);
+        }
+        let slots = self.top_frame().slots;
+        self.close_upvalues(slots);
+        let start = self.stack_top - arity as usize - 1;
+        self.values.copy_within(start..self.stack_top, slots);
+        self.stack_top = slots + arity as usize + 1;
+        let frame = self.top_frame();
+        frame.closure = closure;
+        frame.ip = entry_point.unwrap() as isize - 1;
+        Ok(())
+    }
+
+    // a finished coroutine keeps returning nil
+    fn resume(&mut self, mut coroutine: GC<Coroutine>, arity: u8) -> Result<(), String> {
+        if arity != 0 {
+            return err!("Expected 0 arguments but got {}.", arity);
+        }
+        match coroutine.state {
+            CoroutineState::Done => {
+                self.values[self.stack_top - 1] = Value::Nil;
+                return Ok(());
+            }
+            CoroutineState::Running => return err!("Generator is already running."),
+            CoroutineState::Suspended => (),
+        }
+        if self.frames.len() == self.max_frames {
+            return Err(self.stack_overflow());
+        }
+        let slots = self.stack_top - 1;
+        self.stack_top = slots;
+        for &value in coroutine.stack.iter() {
+            self.push(value);
+        }
+        // reopen captured locals, taking in changes made while suspended; the
+        // generator is on top, so they go in front of the other open upvalues
+        while let Some((offset, mut upvalue)) = coroutine.upvalues.pop() {
+            if let Upvalue::Closed(value) = *upvalue {
+                self.values[slots + offset] = value;
+            }
+            *upvalue = Upvalue::Open(slots + offset, self.open_upvalues);
+            self.open_upvalues = Some(upvalue);
         }
+        coroutine.state = CoroutineState::Running;
+        self.frames.push(CallFrame {
+            ip: coroutine.ip,
+            slots,
+            closure: coroutine.closure,
+            coroutine: Some(coroutine),
+            then_call: None,
+        });
+        Ok(())
+    }
 
-        if self.frames.len() == MAX_FRAMES {
-            return err!("Stack overflow.");
+    // only yields from the generator's own frame are supported
+    fn yield_value(&mut self) -> Result<(), String> {
+        let value = self.pop();
+        let frame = self
+            .frames
+            .pop()
+            .ok_or("Can only yield from a generator.")?;
+        let mut coroutine = frame.coroutine.ok_or("Can only yield from a generator.")?;
+        let before_count = coroutine.byte_count();
+        // captured locals hold their values while suspended, and are reopened
+        // on resume, so closures and the generator keep sharing them
+        let mut current = self.open_upvalues;
+        while let Some(link) = current {
+            match *link {
+                Upvalue::Open(location, next) if location >= frame.slots => {
+                    coroutine.upvalues.push((location - frame.slots, link));
+                    current = next;
+                }
+                _ => break,
+            }
         }
-        self.frames
-            .push(CallFrame::new(self.stack_top - arity as usize - 1, closure));
+        self.close_upvalues(frame.slots);
+        coroutine.stack.clear();
+        coroutine
+            .stack
+            .extend_from_slice(&self.values[frame.slots..self.stack_top]);
+        coroutine.ip = frame.ip;
+        coroutine.state = CoroutineState::Suspended;
+        self.heap
+            .increase_byte_count(coroutine.byte_count() - before_count);
+        self.stack_top = frame.slots;
+        self.push(value);
         Ok(())
     }
 
+    // only called on overflow, to see if the top frames all run the same function
+    fn stack_overflow(&self) -> String {
+        let function = self.frames[self.max_frames - 1].closure.function;
+        if self.frames[self.max_frames.saturating_sub(RECURSION_HINT_DEPTH)..]
+            .iter()
+            .all(|frame| frame.closure.function == function)
+        {
+            format!(
+                "Stack overflow. Possible infinite recursion in {}.",
+                self.show(&*function)
+            )
+        } else {
+            String::from("Stack overflow.")
+        }
+    }
+
     fn call_value(&mut self, callee: Value, arity: u8) -> Result<(), String> {
         if let Value::Object(handle) = callee {
             match handle.kind() {
@@ -247,7 +945,7 @@ impl VM {
                     let obj = Class::as_gc(&handle);
                     let instance = self.new_obj(Instance::new(obj));
                     self.values[self.stack_top - arity as usize - 1] = Value::from(instance);
-                    if let Some(init) = obj.methods.get(self.init_string) {
+                    if let Some(init) = obj.method(self.common_strings.init) {
                         return self.call(init, arity);
                     } else if arity > 0 {
                         return err!("Expected no arguments but got {}.", arity);
@@ -258,8 +956,17 @@ impl VM {
                 Kind::Closure => {
                     return self.call(Closure::as_gc(&handle), arity);
                 }
+                Kind::Coroutine => {
+                    return self.resume(Coroutine::as_gc(&handle), arity);
+                }
                 Kind::Native => {
-                    let result = Native::as_gc(&handle).0(self.tail(arity as usize)?)?;
+                    let native = Native::as_gc(&handle);
+                    let args = &self.values[self.stack_top - arity as usize..self.stack_top];
+                    native.check_args(args)?;
+                    let result = match native.io {
+                        Some(io) => self.run_io_native(io, arity as usize)?,
+                        None => (native.function)(&mut self.heap, args)?,
+                    };
                     self.stack_top -= arity as usize + 1;
                     self.push(result);
                     return Ok(());
@@ -276,26 +983,66 @@ impl VM {
         name: GC<Loxtr>,
         arity: u8,
     ) -> Result<(), String> {
-        match class.methods.get(name) {
+        match class.method(name) {
             None => err!("Undefined property '{}'", *name),
+            Some(method) if method.function.is_getter => self.invoke_getter(method, arity),
             Some(method) => self.call(method, arity),
         }
     }
 
-    fn invoke(&mut self, name: GC<Loxtr>, arity: u8) -> Result<(), String> {
+    // like getting the property and then calling it: the getter runs on a copy
+    // of the receiver and its result is called with the arguments on return
+    fn invoke_getter(&mut self, getter: GC<Closure>, arity: u8) -> Result<(), String> {
+        self.push(self.peek(arity as usize));
+        self.call(getter, 0)?;
+        self.top_frame().then_call = Some(arity);
+        Ok(())
+    }
+
+    fn invoke(&mut self, name: GC<Loxtr>, arity: u8, slot: u16) -> Result<(), String> {
         let value = self.peek(arity as usize);
+        if let Some(string) = Loxtr::nullable(value) {
+            let start = self.stack_top - arity as usize;
+            let result = string_method(
+                &mut self.heap,
+                string,
+                name,
+                &self.values[start..self.stack_top],
+            )?;
+            // replace the string and its arguments
+            self.stack_top = start;
+            self.values[start - 1] = result;
+            return Ok(());
+        }
+        if let Some(method) = Class::nullable(value).and_then(|class| class.static_method(name)) {
+            // like calling the method after getting it from the class
+            self.values[self.stack_top - arity as usize - 1] = Value::from(method);
+            return self.call(method, arity);
+        }
         let instance = Instance::nullable(value).ok_or("Only instances have methods.")?;
         if let Some(property) = instance.properties.get(name) {
             self.values[self.stack_top - arity as usize - 1] = property;
-            self.call_value(property, arity)
-        } else {
-            self.invoke_from_class(instance.class, name, arity)
+            return self.call_value(property, arity);
+        }
+        let mut function = self.top_frame().closure.function;
+        match function.chunk.read_cache(slot) {
+            Some((class, method)) if class == instance.class => self.call(method, arity),
+            _ => match instance.class.method(name) {
+                None => err!("Undefined property '{}'", *name),
+                Some(method) if method.function.is_getter => self.invoke_getter(method, arity),
+                Some(method) => {
+                    function.chunk.write_cache(slot, instance.class, method);
+                    self.call(method, arity)
+                }
+            },
         }
     }
 
     fn bind_method(&mut self, class: GC<Class>, name: GC<Loxtr>) -> Result<(), String> {
-        match class.methods.get(name) {
+        match class.method(name) {
             None => err!("Undefined property '{}'.", *name),
+            // getters run in place of being bound, on the receiver at the top
+            Some(method) if method.function.is_getter => self.call(method, 0),
             Some(method) => {
                 let instance = GC::from(self.peek(0));
                 let bm = self.new_obj(BoundMethod::new(instance, method));
@@ -306,11 +1053,18 @@ impl VM {
         }
     }
 
-    fn define_method(&mut self, name: GC<Loxtr>) -> Result<(), String> {
-        if let Ok(&[a, method]) = self.tail(2) {
-            let mut class = GC::<Class>::from(a);
+    fn define_method(&mut self, name: GC<Loxtr>, is_static: bool) -> Result<(), String> {
+        if let &[a, b] = self.tail(2)? {
+            let mut class = Class::nullable(a)
+                .ok_or_else(|| format!("Cannot define method '{}' on '{}'.", *name, a))?;
+            let method = Closure::nullable(b)
+                .ok_or_else(|| format!("Method '{}' must be a closure, found '{}'.", *name, b))?;
             let before_count = class.byte_count();
-            class.methods.set(name, GC::from(method));
+            if is_static {
+                class.statics.set(name, method);
+            } else {
+                class.methods.set(name, method);
+            }
             self.heap
                 .increase_byte_count(class.byte_count() - before_count);
             self.pop();
@@ -318,6 +1072,32 @@ impl VM {
         Ok(())
     }
 
+    // suggests the closest global name within two edits
+    fn undefined_variable(&self, name: GC<Loxtr>) -> String {
+        let suggestion = self
+            .globals
+            .keys()
+            .chain(self.builtins.keys())
+            .map(|key| (edit_distance(name.as_ref(), key.as_ref()), key.as_ref()))
+            .filter(|&(distance, _)| distance <= 2)
+            .min();
+        match suggestion {
+            Some((_, key)) => format!("Undefined variable '{}'. Did you mean '{}'?", *name, key),
+            None => format!("Undefined variable '{}'.", *name),
+        }
+    }
+
+    fn unassignable(&self, name: GC<Loxtr>) -> String {
+        if self.builtins.get(name).is_some() {
+            format!(
+                "Cannot assign to builtin '{}', declare a global to shadow it.",
+                *name
+            )
+        } else {
+            self.undefined_variable(name)
+        }
+    }
+
     fn concatenate(&mut self, a: &str, b: &str) -> Value {
         let mut c = String::new();
         c.push_str(a);
@@ -338,113 +1118,161 @@ impl VM {
     }
 
     fn run(&mut self) -> Result<(), String> {
-        loop {
-            let instruction = Op::try_from(self.top_frame().read_byte())?;
-            #[cfg(feature = "trace")]
-            {
-                print!("stack: ");
-                for i in 0..self.stack_top {
-                    print!("{};", &self.values[i]);
-                }
-                println!("");
-
-                // print!("globals: ");
-                // for (k, v) in &self.globals {
-                //     print!("{}:{};", **k, v)
-                // }
-                // println!("");
-
-                let ip = self.top_frame().ip;
-                println!("ip: {}", ip);
-                println!("line: {}", self.top_frame().chunk().lines[ip as usize]);
-                println!("op code: {:?}", instruction);
-                println!();
-            }
-            match instruction {
-                Op::Add => {
-                    if let &[a, b] = self.tail(2)? {
-                        if let (Some(a), Some(b)) = (Loxtr::nullable(a), Loxtr::nullable(b)) {
-                            let c = self.concatenate(a.as_ref(), b.as_ref());
-                            self.stack_top -= 2;
-                            self.push(c);
-                            continue;
-                        }
-
-                        if let (Value::Number(a), Value::Number(b)) = (a, b) {
-                            self.stack_top -= 2;
-                            self.push(Value::from(a + b));
-                            continue;
-                        }
-
-                        return err!(
-                            "Operands must be either numbers or strings, found '{}' and '{}'",
-                            a,
-                            b
-                        );
-                    }
-                }
-                Op::Call => {
-                    let arity = self.top_frame().read_byte();
-                    self.call_value(self.peek(arity as usize), arity)?;
-                }
-                Op::Class => {
-                    let name = self.top_frame().read_string()?;
-                    self.push_traceable(Class::new(name));
-                }
-                Op::CloseUpvalue => {
-                    self.close_upvalues(self.stack_top - 1);
-                    self.pop();
+        while let StepResult::Paused = self.execute()? {}
+        Ok(())
+    }
+
+    // execute a single instruction
+    fn execute(&mut self) -> Result<StepResult, String> {
+        let instruction = Op::try_from(self.top_frame().read_byte())?;
+        if let Some(op_counts) = &mut self.op_counts {
+            op_counts[instruction as usize] += 1;
+        }
+        if !self.breakpoints.is_empty() {
+            self.check_breakpoint();
+        }
+        #[cfg(feature = "trace")]
+        {
+            print!("stack: ");
+            for i in 0..self.stack_top {
+                print!("{};", &self.values[i]);
+            }
+            println!();
+
+            let ip = self.top_frame().ip;
+            println!("ip: {}", ip);
+            println!("line: {}", self.top_frame().chunk().lines[ip as usize]);
+            println!("op code: {:?}", instruction);
+            println!();
+        }
+        match instruction {
+            Op::Add => self.add()?,
+            Op::AddNum => {
+                if let &[Value::Number(a), Value::Number(b)] = self.tail(2)? {
+                    self.stack_top -= 2;
+                    self.push(Value::from(a + b));
+                } else {
+                    self.add()?;
                 }
-                Op::Closure => {
-                    let function = GC::from(self.top_frame().read_constant());
-                    let mut closure = self.push_traceable(Closure::new(function));
-                    let before_count = closure.byte_count();
-                    for _ in 0..function.upvalue_count {
-                        let is_local = self.top_frame().read_byte();
-                        let index = self.top_frame().read_byte() as usize;
-                        closure.upvalues.push(if is_local > 0 {
-                            let location = self.top_frame().slots + index;
-                            self.capture_upvalue(location)
-                        } else {
-                            self.top_frame().closure.upvalues[index]
-                        })
+            }
+            Op::AddStr => {
+                if let &[a, b] = self.tail(2)? {
+                    if let (Some(a), Some(b)) = (Loxtr::nullable(a), Loxtr::nullable(b)) {
+                        let c = self.concatenate(a.as_ref(), b.as_ref());
+                        self.stack_top -= 2;
+                        self.push(c);
+                    } else {
+                        self.add()?;
                     }
-                    self.heap
-                        .increase_byte_count(closure.byte_count() - before_count)
                 }
-                Op::Constant => {
-                    let value = self.top_frame().read_constant();
-                    self.push(value)
-                }
-                Op::DefineGlobal => {
-                    let name = self.top_frame().read_string()?;
-                    self.globals.set(name, self.peek(0));
-                    self.pop();
+            }
+            Op::BuildList => {
+                let count = self.top_frame().read_byte() as usize;
+                let items = self.tail(count)?.to_vec();
+                // the items stay on the stack while the list is allocated
+                let list = self.new_obj(List::new(items));
+                self.stack_top -= count;
+                self.push(Value::from(list));
+            }
+            Op::Index => {
+                if let &[list, index] = self.tail(2)? {
+                    let list = List::nullable(list).ok_or("Only lists can be indexed.")?;
+                    let value = list.items[list_index(&list, index)?];
+                    self.stack_top -= 2;
+                    self.push(value);
                 }
-                Op::Divide => binary_op!(self, a, b, a / b),
-                Op::Equal => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(Value::from(a == b));
+            }
+            Op::SetIndex => {
+                if let &[list, index, value] = self.tail(3)? {
+                    let mut list = List::nullable(list).ok_or("Only lists can be indexed.")?;
+                    let index = list_index(&list, index)?;
+                    list.items[index] = value;
+                    self.stack_top -= 3;
+                    self.push(value);
                 }
-                Op::False => self.push(Value::False),
-                Op::GetGlobal => {
-                    let name = self.top_frame().read_string()?;
-                    if let Some(value) = self.globals.get(name) {
-                        self.push(value);
+            }
+            Op::Call => {
+                let arity = self.top_frame().read_byte();
+                self.call_value(self.peek(arity as usize), arity)?;
+            }
+            Op::TailCall => {
+                let arity = self.top_frame().read_byte();
+                self.tail_call(self.peek(arity as usize), arity)?;
+            }
+            Op::Class => {
+                let name = self.top_frame().read_string()?;
+                self.push_traceable(Class::new(name));
+            }
+            Op::CloseUpvalue => {
+                self.close_upvalues(self.stack_top - 1);
+                self.pop();
+            }
+            Op::Closure => {
+                let function = GC::from(self.top_frame().read_constant());
+                let mut closure = self.push_traceable(Closure::new(function));
+                let before_count = closure.byte_count();
+                for _ in 0..function.upvalue_count {
+                    let is_local = self.top_frame().read_byte();
+                    let index = self.top_frame().read_byte() as usize;
+                    closure.upvalues.push(if is_local > 0 {
+                        let location = self.top_frame().slots + index;
+                        self.capture_upvalue(location)
                     } else {
-                        return err!("Undefined variable '{}'.", *name);
-                    }
-                }
-                Op::GetLocal => {
-                    let index = self.top_frame().slots + self.top_frame().read_byte() as usize;
-                    self.push(self.values[index])
+                        self.top_frame().closure.upvalues[index]
+                    })
                 }
-                Op::GetProperty => {
-                    let value = self.peek(0);
-                    let instance = Instance::nullable(value)
-                        .ok_or(String::from("Only instances have properties."))?;
-                    let name = self.top_frame().read_string()?;
+                self.heap
+                    .increase_byte_count(closure.byte_count() - before_count)
+            }
+            Op::Constant => {
+                let value = self.top_frame().read_constant();
+                self.push(value)
+            }
+            Op::DefineGlobal => {
+                let name = self.top_frame().read_string()?;
+                self.globals.set(name, self.peek(0));
+                self.pop();
+            }
+            Op::DeepEqual => {
+                let b = self.pop();
+                let a = self.pop();
+                self.push(Value::from(deep_equal(a, b)));
+            }
+            Op::Divide => binary_op!(self, a, b, a / b),
+            Op::Dup => self.push(self.peek(0)),
+            Op::Equal => {
+                let a = self.pop();
+                let b = self.pop();
+                self.push(Value::from(a == b));
+            }
+            Op::False => self.push(Value::False),
+            Op::GetGlobal => {
+                let name = self.top_frame().read_string()?;
+                if let Some(value) = self.globals.get(name).or_else(|| self.builtins.get(name)) {
+                    if value == Value::Undefined {
+                        return err!("Variable '{}' used before assignment.", *name);
+                    }
+                    self.push(value);
+                } else {
+                    return Err(self.undefined_variable(name));
+                }
+            }
+            Op::GetLocal => {
+                let index = self.top_frame().slots + self.top_frame().read_byte() as usize;
+                self.push(self.values[index])
+            }
+            Op::GetProperty => {
+                let value = self.peek(0);
+                let name = self.top_frame().read_string()?;
+                if let Some(string) = Loxtr::nullable(value) {
+                    self.values[self.stack_top - 1] = string_property(string, name)?;
+                } else if let Some(method) =
+                    Class::nullable(value).and_then(|class| class.static_method(name))
+                {
+                    self.values[self.stack_top - 1] = Value::from(method);
+                } else {
+                    let instance = Instance::nullable(value)
+                        .ok_or(String::from("Only instances have properties."))?;
                     if let Some(value) = instance.properties.get(name) {
                         // replace instance
                         self.values[self.stack_top - 1] = value;
@@ -452,121 +1280,225 @@ impl VM {
                         self.bind_method(instance.class, name)?;
                     }
                 }
-                Op::GetSuper => {
-                    let name = self.top_frame().read_string()?;
-                    let super_class = GC::from(self.pop());
-                    self.bind_method(super_class, name)?;
-                }
-                Op::GetUpvalue => {
-                    let value = match *self.top_frame().read_upvalue() {
-                        Upvalue::Open(index, _) => self.values[index],
-                        Upvalue::Closed(value) => value,
-                    };
-                    self.push(value);
+            }
+            Op::GetSuper => {
+                let name = self.top_frame().read_string()?;
+                let super_class = GC::from(self.pop());
+                self.bind_method(super_class, name)?;
+            }
+            Op::GetThis => {
+                let slots = self.top_frame().slots;
+                self.push(self.values[slots]);
+            }
+            Op::GetUpvalue => {
+                let value = match *self.top_frame().read_upvalue() {
+                    Upvalue::Open(index, _) => self.values[index],
+                    Upvalue::Closed(value) => value,
+                };
+                self.push(value);
+            }
+            Op::Greater => {
+                binary_op!(self, a, b, a > b)
+            }
+            Op::GreaterEqual => binary_op!(self, a, b, a >= b),
+            Op::Inherit => {
+                if let &[a, b] = self.tail(2)? {
+                    let super_class =
+                        Class::nullable(a).ok_or(String::from("Super class must be a class."))?;
+                    let mut sub_class =
+                        Class::nullable(b).ok_or(String::from("Sub class must be a class."))?;
+                    // statics are inherited too, so subclasses can override them
+                    sub_class.super_class = Some(super_class);
+                    self.pop();
                 }
-                Op::Greater => {
-                    binary_op!(self, a, b, a > b)
-                }
-                Op::Inherit => {
-                    if let &[a, b] = self.tail(2)? {
-                        let super_class = Class::nullable(a)
-                            .ok_or(String::from("Super class must be a class."))?;
-                        let mut sub_class =
-                            Class::nullable(b).ok_or(String::from("Sub class must be a class."))?;
-                        let bytes_before = sub_class.byte_count();
-                        sub_class.methods.set_all(&super_class.methods);
-                        self.heap
-                            .increase_byte_count(sub_class.byte_count() - bytes_before);
-                        self.pop();
-                    }
+            }
+            Op::Invoke => {
+                let name = self.top_frame().read_string()?;
+                let arity = self.top_frame().read_byte();
+                let slot = self.top_frame().read_short();
+                self.invoke(name, arity, slot)?;
+            }
+            Op::InvokeThis => {
+                let name = self.top_frame().read_string()?;
+                let arity = self.top_frame().read_byte() as usize;
+                let slot = self.top_frame().read_short();
+                // slide the receiver in under the arguments
+                let start = self.stack_top - arity;
+                self.values.copy_within(start..self.stack_top, start + 1);
+                self.values[start] = self.values[self.top_frame().slots];
+                self.stack_top += 1;
+                self.invoke(name, arity as u8, slot)?;
+            }
+            Op::Jump => self.top_frame().jump_forward(),
+            Op::JumpIfFalse => {
+                if self.is_falsey(self.peek(0)) {
+                    self.top_frame().jump_forward();
+                } else {
+                    self.top_frame().ip += 2;
                 }
-                Op::Invoke => {
-                    let name = self.top_frame().read_string()?;
-                    let arity = self.top_frame().read_byte();
-                    self.invoke(name, arity)?;
+            }
+            Op::JumpLong => self.top_frame().jump_forward_long(),
+            Op::JumpIfFalseLong => {
+                if self.is_falsey(self.peek(0)) {
+                    self.top_frame().jump_forward_long();
+                } else {
+                    self.top_frame().ip += 2;
                 }
-                Op::Jump => self.top_frame().jump_forward(),
-                Op::JumpIfFalse => {
-                    if self.peek(0).is_falsey() {
-                        self.top_frame().jump_forward();
-                    } else {
-                        self.top_frame().ip += 2;
-                    }
+            }
+            Op::Less => binary_op!(self, a, b, a < b),
+            Op::LessEqual => binary_op!(self, a, b, a <= b),
+            Op::Loop => self.top_frame().jump_back(),
+            Op::LoopLong => self.top_frame().jump_back_long(),
+            Op::Method => {
+                let name = self.top_frame().read_string()?;
+                self.define_method(name, false)?
+            }
+            Op::MethodDynamic => {
+                let closure = self.pop();
+                let name = self.pop();
+                let name = Loxtr::nullable(name)
+                    .ok_or_else(|| format!("Method name must be a string, found '{}'.", name))?;
+                if name == self.common_strings.init {
+                    return err!("An initializer cannot have a computed name.");
                 }
-                Op::Less => binary_op!(self, a, b, a < b),
-                Op::Loop => self.top_frame().jump_back(),
-                Op::Method => {
-                    let name = self.top_frame().read_string()?;
-                    self.define_method(name)?
+                self.push(closure);
+                self.define_method(name, false)?
+            }
+            Op::Modulo => binary_op!(self, a, b, a % b),
+            Op::Multiply => binary_op!(self, a, b, a * b),
+            Op::Negative => {
+                if let Value::Number(a) = self.peek(0) {
+                    self.values[self.stack_top - 1] = Value::from(-a);
+                } else {
+                    return err!("Operand must be a number.");
                 }
-                Op::Multiply => binary_op!(self, a, b, a * b),
-                Op::Negative => {
-                    if let Value::Number(a) = self.peek(0) {
-                        self.values[self.stack_top - 1] = Value::from(-a);
-                    } else {
-                        return err!("Operand must be a number.");
-                    }
+            }
+            Op::Nil => self.push(Value::Nil),
+            Op::Not => {
+                let pop = self.pop();
+                self.push(Value::from(self.is_falsey(pop)));
+            }
+            Op::Power => binary_op!(self, a, b, a.powf(b)),
+            Op::PopN => {
+                let count = self.top_frame().read_byte();
+                self.stack_top -= count as usize;
+            }
+            Op::Pop => {
+                self.pop();
+            }
+            Op::Print => {
+                let value = self.pop();
+                let text = self.show(value);
+                self.write_output(&text, "\n")?;
+            }
+            Op::Return => {
+                let result = self.pop();
+                let then_call = self.top_frame().then_call;
+                self.unwind_to(self.frames.len() - 1);
+                if let Some(arity) = then_call {
+                    // the result replaces the receiver under the arguments
+                    self.values[self.stack_top - arity as usize - 1] = result;
+                    self.call_value(result, arity)?;
+                    return Ok(StepResult::Paused);
                 }
-                Op::Nil => self.push(Value::Nil),
-                Op::Not => {
-                    let pop = &self.pop();
-                    self.push(Value::from(pop.is_falsey()));
+                // the result of the script is left for eval
+                self.push(result);
+                if self.frames.is_empty() {
+                    return Ok(StepResult::Finished);
                 }
-                Op::Pop => {
-                    self.pop();
+            }
+            Op::SetGlobal => {
+                let name = self.top_frame().read_string()?;
+                if self.globals.set(name, self.peek(0)) {
+                    self.globals.delete(name);
+                    return Err(self.unassignable(name));
                 }
-                Op::Print => println!("{}", self.pop()),
-                Op::Return => {
-                    let result = self.pop();
-                    let location = self.top_frame().slots;
-                    self.close_upvalues(location);
-                    self.frames.pop();
-                    if self.frames.is_empty() {
-                        self.pop();
-                        return Ok(());
-                    }
-                    self.stack_top = location;
-                    self.push(result);
+            }
+            Op::SetGlobalPop => {
+                let name = self.top_frame().read_string()?;
+                if self.globals.set(name, self.peek(0)) {
+                    self.globals.delete(name);
+                    return Err(self.unassignable(name));
                 }
-                Op::SetGlobal => {
+                self.pop();
+            }
+            Op::SetLocalPop => {
+                let index = self.top_frame().read_byte() as usize;
+                self.values[self.top_frame().slots + index] = self.pop();
+            }
+            Op::SetLocal => {
+                let index = self.top_frame().read_byte() as usize;
+                self.values[self.top_frame().slots + index] = self.peek(0);
+            }
+            Op::SetProperty => {
+                if let &[a, b] = self.tail(2)? {
+                    let mut instance =
+                        Instance::nullable(a).ok_or(String::from("Only instances have fields."))?;
                     let name = self.top_frame().read_string()?;
-                    if self.globals.set(name, self.peek(0)) {
-                        self.globals.delete(name);
-                        return err!("Undefined variable '{}'.", *name);
-                    }
-                }
-                Op::SetLocal => {
-                    let index = self.top_frame().read_byte() as usize;
-                    self.values[self.top_frame().slots + index] = self.peek(0);
-                }
-                Op::SetProperty => {
-                    if let &[a, b] = self.tail(2)? {
-                        let mut instance = Instance::nullable(a)
-                            .ok_or(String::from("Only instances have fields."))?;
-                        let before_count = instance.byte_count();
-                        instance.properties.set(self.top_frame().read_string()?, b);
-                        self.heap
-                            .increase_byte_count(instance.byte_count() - before_count);
-                        self.stack_top -= 2;
-                        self.push(b);
+                    if instance
+                        .class
+                        .method(name)
+                        .is_some_and(|method| method.function.is_getter)
+                    {
+                        return err!("Cannot assign to getter '{}'.", *name);
                     }
+                    let before_count = instance.byte_count();
+                    instance.properties.set(name, b);
+                    self.heap
+                        .increase_byte_count(instance.byte_count() - before_count);
+                    self.stack_top -= 2;
+                    self.push(b);
                 }
-                Op::SetUpvalue => {
-                    let mut upvalue = self.top_frame().read_upvalue();
-                    match *upvalue {
-                        Upvalue::Closed(_) => *upvalue = Upvalue::Closed(self.peek(0)),
-                        Upvalue::Open(index, _) => self.values[index] = self.peek(0),
-                    }
+            }
+            Op::SetUpvalue => {
+                let mut upvalue = self.top_frame().read_upvalue();
+                match *upvalue {
+                    Upvalue::Closed(_) => *upvalue = Upvalue::Closed(self.peek(0)),
+                    Upvalue::Open(index, _) => self.values[index] = self.peek(0),
                 }
-                Op::Subtract => binary_op!(self, a, b, a - b),
-                Op::SuperInvoke => {
-                    let name = self.top_frame().read_string()?;
-                    let arity = self.top_frame().read_byte();
-                    let super_class = GC::from(self.pop());
-                    self.invoke_from_class(super_class, name, arity)?;
+            }
+            Op::SmallInt => {
+                let value = self.top_frame().read_byte();
+                self.push(Value::from(value as f64))
+            }
+            Op::StaticMethod => {
+                let name = self.top_frame().read_string()?;
+                self.define_method(name, true)?
+            }
+            Op::Stringify => {
+                let value = self.peek(0);
+                if Loxtr::nullable(value).is_none() {
+                    let string = self.heap.intern(self.show(value));
+                    self.values[self.stack_top - 1] = Value::from(string);
                 }
-                Op::True => self.push(Value::True),
             }
+            Op::Subtract => binary_op!(self, a, b, a - b),
+            Op::Yield => self.yield_value()?,
+            Op::SuperInvoke => {
+                let name = self.top_frame().read_string()?;
+                let arity = self.top_frame().read_byte();
+                let super_class = GC::from(self.pop());
+                self.invoke_from_class(super_class, name, arity)?;
+            }
+            Op::True => self.push(Value::True),
+            Op::Undefined => self.push(Value::Undefined),
+        }
+        Ok(StepResult::Paused)
+    }
+
+    // drop the frames above depth, closing their upvalues and finishing their
+    // coroutines
+    fn unwind_to(&mut self, depth: usize) {
+        while self.frames.len() > depth {
+            let Some(frame) = self.frames.pop() else {
+                return;
+            };
+            if let Some(mut coroutine) = frame.coroutine {
+                coroutine.state = CoroutineState::Done;
+                coroutine.stack.clear();
+            }
+            self.close_upvalues(frame.slots);
+            self.stack_top = frame.slots;
         }
     }
 
@@ -583,26 +1515,165 @@ impl VM {
         self.open_upvalues = None;
     }
 
-    pub fn interpret(&mut self, source: &str) -> Result<(), String> {
-        let function = compile(source, &mut self.heap)?;
+    fn compile(&mut self, source: &str) -> Result<GC<Function>, InterpretError> {
+        let start = Instant::now();
+        let mut warnings = Vec::new();
+        let result = if self.strict {
+            compile_strict(source, &mut self.heap, &mut warnings)
+        } else {
+            compile(source, &mut self.heap)
+        };
+        self.report_compile_time(start);
+        self.report_warnings(&warnings);
+        result.map_err(InterpretError::Compile)
+    }
+
+    fn report_warnings(&mut self, warnings: &[String]) {
+        for warning in warnings {
+            // like runtime errors, warnings go to the error output
+            let _ = writeln!(self.error_output, "{}", warning);
+        }
+    }
+
+    fn report_compile_time(&mut self, start: Instant) {
+        // losing the timing message is no reason to fail
+        let _ = writeln!(
+            self.output,
+            "Compilation finished in {} ns.",
+            Instant::now().duration_since(start).as_nanos()
+        );
+    }
+
+    // compile without running
+    pub fn check(&mut self, source: &str) -> Result<(), InterpretError> {
+        self.compile(source)?;
+        Ok(())
+    }
+
+    pub fn interpret(&mut self, source: &str) -> Result<(), InterpretError> {
+        let function = self.compile(source)?;
+        self.run_function(function)?;
+        Ok(())
+    }
+
+    // the value of a final expression statement, or nil, which `to_string`
+    // shows the way print would; collections may free it on later calls
+    pub fn eval(&mut self, source: &str) -> Result<Value, InterpretError> {
+        let start = Instant::now();
+        let mut warnings = Vec::new();
+        let result = compile_eval(source, &mut self.heap, self.strict, &mut warnings);
+        self.report_compile_time(start);
+        self.report_warnings(&warnings);
+        let function = result.map_err(InterpretError::Compile)?;
+        self.run_function(function)
+    }
+
+    // globals defined by the prelude persist for later calls to interpret
+    pub fn with_prelude(&mut self, prelude_source: &str) -> Result<(), InterpretError> {
+        self.interpret(prelude_source)
+    }
+
+    // compile a program and prepare to step through it
+    pub fn load(&mut self, source: &str) -> Result<(), InterpretError> {
+        let function = self.compile(source)?;
+        self.load_function(function)
+    }
+
+    pub fn step(&mut self) -> Result<StepResult, InterpretError> {
+        if self.frames.is_empty() {
+            return Ok(StepResult::Finished);
+        }
+        let result = self.execute().map_err(|msg| self.runtime_error(msg))?;
+        if result == StepResult::Finished {
+            self.pop();
+        }
+        Ok(result)
+    }
+
+    fn load_function(&mut self, function: GC<Function>) -> Result<(), InterpretError> {
         self.push(Value::from(function));
         let closure = self.new_obj(Closure::new(function));
         self.pop();
         self.push(Value::from(closure));
-        self.call(closure, 0)?;
-        if let Err(msg) = self.run() {
-            eprintln!("Error: {}", msg);
-            while let Some(frame) = &self.frames.pop() {
-                eprintln!(
-                    "  at {} line {}",
-                    *frame.closure.function,
-                    frame.chunk().lines[frame.ip as usize]
-                )
+        self.call(closure, 0).map_err(InterpretError::Runtime)
+    }
+
+    fn run_function(&mut self, function: GC<Function>) -> Result<Value, InterpretError> {
+        self.load_function(function)?;
+        self.run().map_err(|msg| self.runtime_error(msg))?;
+        Ok(self.pop())
+    }
+
+    fn runtime_error(&mut self, msg: String) -> InterpretError {
+        let mut trace = format!("Error: {}", msg);
+        // runs of identical frames, as in deep recursion, are collapsed
+        let mut last = String::new();
+        let mut repeats = 0;
+        while let Some(frame) = &self.frames.pop() {
+            // a failed generator is finished, not left running
+            if let Some(mut coroutine) = frame.coroutine {
+                coroutine.state = CoroutineState::Done;
+                coroutine.stack.clear();
             }
-            self.reset_stack();
-            err!("Runtime error!")
-        } else {
-            Ok(())
+            let (line, column) = frame.chunk().location(frame.ip as usize);
+            let function = self.show(&*frame.closure.function);
+            let at = format!("\n  at {} [line {}, col {}]", function, line, column);
+            if at == last {
+                repeats += 1;
+                continue;
+            }
+            push_repeats(&mut trace, repeats, &last);
+            repeats = 0;
+            trace.push_str(&at);
+            last = at;
+            #[cfg(feature = "debug_info")]
+            for (name, slot, range) in frame.closure.function.locals.iter() {
+                if range.contains(&(frame.ip as usize)) {
+                    let value = self.values[frame.slots + *slot as usize];
+                    trace.push_str(&format!("\n    local {} = {}", name, self.show(value)));
+                }
+            }
+        }
+        push_repeats(&mut trace, repeats, &last);
+        self.reset_stack();
+        let _ = writeln!(self.error_output, "{}", trace);
+        InterpretError::Runtime(trace)
+    }
+}
+
+// a single repeat is shown, longer runs are counted
+fn push_repeats(trace: &mut String, repeats: usize, at: &str) {
+    match repeats {
+        0 => (),
+        1 => trace.push_str(at),
+        _ => trace.push_str(&format!("\n  ... {} more frames like the above", repeats)),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TruthinessMode {
+    // only nil and false are falsey
+    Lox,
+    // zero and the empty string are falsey as well
+    Loose,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StepResult {
+    Paused,
+    Finished,
+}
+
+#[derive(Debug)]
+pub enum InterpretError {
+    Compile(String),
+    Runtime(String),
+}
+
+impl Display for InterpretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InterpretError::Compile(msg) | InterpretError::Runtime(msg) => msg.fmt(f),
         }
     }
 }
@@ -611,6 +1682,11 @@ impl VM {
 mod tests {
     use super::*;
 
+    fn global(vm: &mut VM, name: &str) -> Option<Value> {
+        let key = vm.heap.intern_copy(name);
+        vm.globals.get(key)
+    }
+
     #[test]
     fn no_error_on_init() {
         VM::new(Heap::new());
@@ -724,6 +1800,41 @@ mod tests {
         assert!(result.is_ok(), "{}", result.unwrap_err());
     }
 
+    #[test]
+    fn nested_if_statements() {
+        let test = "
+        var x = 0; var y = 0; var z = 0;
+        for (var i = 0; i < 4; i = i + 1) {
+            if (i < 2) { if (i < 1) x = x + 1; else y = y + 1; } else z = z + 1;
+        }
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        for (name, expected) in [("x", 1.0), ("y", 1.0), ("z", 2.0)] {
+            assert_eq!(global(&mut vm, name), Some(Value::Number(expected)));
+        }
+    }
+
+    #[test]
+    fn assignment_statements() {
+        let test = "
+        var x = 1;
+        fun f() { var y = 2; y = y + x; return y; }
+        x = f();
+        var a = false;
+        a and (x = 10);
+        var b = x;
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        for (name, expected) in [("x", 3.0), ("b", 3.0)] {
+            assert_eq!(global(&mut vm, name), Some(Value::Number(expected)));
+        }
+        assert!(vm.interpret("undefined = 1;").is_err());
+    }
+
     #[test]
     fn upvalues() {
         let test = "
@@ -743,6 +1854,29 @@ mod tests {
         assert!(result.is_ok(), "{}", result.unwrap_err());
     }
 
+    #[test]
+    fn closures_capture_loop_variable() {
+        let test = "
+        var f0; var f1; var f2;
+        for (var i = 0; i < 3; i = i + 1) {
+            fun f() { return i; }
+            if (i == 0) f0 = f; else if (i == 1) f1 = f; else f2 = f;
+        }
+        var a = f0(); var b = f1(); var c = f2();
+        var count = 0;
+        for (var i = 0; i < 10; i = i + 1) {
+            i = i + 1;
+            count = count + 1;
+        }
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        for (name, expected) in [("a", 0.0), ("b", 1.0), ("c", 2.0), ("count", 5.0)] {
+            assert_eq!(global(&mut vm, name), Some(Value::Number(expected)));
+        }
+    }
+
     #[test]
     fn classes() {
         let test = "
@@ -772,6 +1906,144 @@ mod tests {
         assert!(result.is_ok(), "{}", result.unwrap_err());
     }
 
+    #[test]
+    fn polymorphic_invocations() {
+        let test = "
+        class A { name() { return \"a\"; } }
+        class B < A {}
+        class C { name() { return \"c\"; } }
+        var names = \"\";
+        fun describe(x) { names = names + x.name(); }
+        for (var i = 0; i < 4; i = i + 1) {
+            describe(A()); describe(C()); describe(B()); describe(A());
+        }
+        fun field() { return \"f\"; }
+        var c = C();
+        c.name = field;
+        describe(c);
+        describe(C());
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let expected = vm.heap.intern_copy("acaaacaaacaaacaafc");
+        assert_eq!(global(&mut vm, "names"), Some(Value::from(expected)));
+    }
+
+    #[test]
+    fn prelude() {
+        let mut vm = VM::new(Heap::new());
+        let result = vm.with_prelude("fun double(x) { return x * 2; }");
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let result = vm.interpret("print double(21); var x = double(21);");
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(global(&mut vm, "x"), Some(Value::from(42.0)));
+        assert!(vm.with_prelude("fun double(x) {").is_err());
+    }
+
+    #[test]
+    fn suggest_global_names() {
+        assert_eq!(edit_distance("clok", "clock"), 1);
+        assert_eq!(edit_distance("lenght", "length"), 2);
+        assert_eq!(edit_distance("", "abc"), 3);
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret("print clok;");
+        assert!(matches!(
+            result,
+            Err(InterpretError::Runtime(msg))
+                if msg.starts_with("Error: Undefined variable 'clok'. Did you mean 'clock'?")
+        ));
+        let result = vm.interpret("print tick;");
+        assert!(matches!(
+            result,
+            Err(InterpretError::Runtime(msg)) if msg.starts_with("Error: Undefined variable 'tick'.\n")
+        ));
+    }
+
+    #[test]
+    fn used_before_assignment() {
+        let mut vm = VM::new(Heap::new());
+        vm.set_strict(true);
+        assert!(vm.interpret("var a; var b = nil; print b;").is_ok());
+        let result = vm.interpret("print a;");
+        assert!(matches!(
+            result,
+            Err(InterpretError::Runtime(msg))
+                if msg.starts_with("Error: Variable 'a' used before assignment.")
+        ));
+        assert!(vm.interpret("a = 1; print a;").is_ok());
+        assert!(vm.interpret("{ var c; print c; }").is_ok());
+
+        // without strict mode, declared globals start out nil
+        vm.set_strict(false);
+        assert!(vm.interpret("var d; print d;").is_ok());
+    }
+
+    #[test]
+    fn string_interpolation() {
+        let test = "
+        var x = 1;
+        var s = \"x={x}\";
+        var braces = \"{{}}\";
+        var empty = \"{}\";
+        fun f(y) { return \"{y}{y}!\"; }
+        var t = f(nil);
+        var sum = \"sum={x + 2}\";
+        var nested = \"<{ \"[{x}]\" + f(x) }>\";
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let expected = [
+            ("s", "x=1"),
+            ("braces", "{}"),
+            ("empty", "{}"),
+            ("t", "nilnil!"),
+            ("sum", "sum=3"),
+            ("nested", "<[1]11!>"),
+        ];
+        for (name, expected) in expected {
+            let value = Value::from(vm.heap.intern_copy(expected));
+            assert_eq!(global(&mut vm, name), Some(value), "{}", name);
+        }
+        let result = vm.interpret("print \"{1 + 2\";");
+        assert!(matches!(result, Err(InterpretError::Compile(_))));
+    }
+
+    #[test]
+    fn print_cyclic_instance() {
+        let test = "
+        class A {}
+        var a = A();
+        a.self = a;
+        print a;
+        var s = \"{a}\";
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let expected = vm.heap.intern_copy("<<class A> instance>");
+        assert_eq!(global(&mut vm, "s"), Some(Value::from(expected)));
+    }
+
+    #[test]
+    fn function_details() {
+        let test = "
+        fun f(a, b) { return a + b; }
+        var simple = \"{f}\";
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        vm.set_function_details(true);
+        let result = vm.interpret("var detailed = \"{f}\";");
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        for (name, expected) in [("simple", "<fn f>"), ("detailed", "<fn f(2/0)>")] {
+            let value = Value::from(vm.heap.intern_copy(expected));
+            assert_eq!(global(&mut vm, name), Some(value), "{}", name);
+        }
+    }
+
     #[test]
     fn clock() {
         let test = "
@@ -783,12 +2055,1512 @@ mod tests {
     }
 
     #[test]
-    fn string_equality() {
+    fn constants_across_segments() {
+        let mut test = String::from("var sum = 0;\n");
+        for i in 0..400 {
+            test.push_str(&format!("sum = sum + {};\n", 1000 + i));
+        }
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(&test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(global(&mut vm, "sum"), Some(Value::from(479800.0)));
+    }
+
+    #[test]
+    fn recursion_hint() {
+        let mut vm = VM::new(Heap::new());
+        match vm.interpret("fun f(n) { f(n + 1); } f(0);") {
+            Err(InterpretError::Runtime(msg)) => {
+                assert!(
+                    msg.starts_with("Error: Stack overflow. Possible infinite recursion in <fn f")
+                )
+            }
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+        let test = "fun g(n) { h(n); } fun h(n) { g(n); } g(0);";
+        match vm.interpret(test) {
+            Err(InterpretError::Runtime(msg)) => {
+                assert!(msg.starts_with("Error: Stack overflow.\n"))
+            }
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn collapsed_trace() {
+        let mut vm = VM::new(Heap::new());
+        let test = "fun tc(n) { return n == 0 ? \"done\" : tc(n - 1); }\ntc(100000);";
+        match vm.interpret(test) {
+            Err(InterpretError::Runtime(msg)) => {
+                let lines: Vec<&str> = msg
+                    .lines()
+                    .skip(1)
+                    .filter(|line| !line.starts_with("    local"))
+                    .collect();
+                assert_eq!(
+                    lines,
+                    [
+                        "  at <fn tc> [line 1, col 45]",
+                        "  ... 1022 more frames like the above",
+                        "  at <script> [line 2, col 10]",
+                    ]
+                );
+            }
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+        // a single repeat is not worth a summary
+        match vm.interpret("fun f(n) { if (n > 0) f(n - 1); else nil(); }\nf(2);") {
+            Err(InterpretError::Runtime(msg)) => {
+                assert_eq!(msg.matches("at <fn f> [line 1, col 30]").count(), 2);
+                assert!(!msg.contains("more frames"), "{}", msg);
+            }
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn breakpoints() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let test = "fun f() {
+            var sum = 0;
+            for (var i = 0; i < 3; i = i + 1) {
+                sum = sum + i;
+            }
+            return sum;
+        }
+        f();";
+        let hits = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new(Heap::new());
+        vm.add_breakpoint("f", 4);
+        let collector = hits.clone();
+        vm.on_breakpoint(move |name, line, slots| {
+            collector
+                .borrow_mut()
+                .push((String::from(name), line, slots[3]))
+        });
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let expected: Vec<(String, u16, Value)> = (0..3)
+            .map(|i| (String::from("f"), 4, Value::from(i as f64)))
+            .collect();
+        assert_eq!(*hits.borrow(), expected);
+    }
+
+    #[test]
+    fn single_steps() {
+        let mut vm = VM::new(Heap::new());
+        vm.set_output_enabled(false);
+        assert!(vm.load("print 1 + 2;").is_ok());
+        // SmallInt, SmallInt, Add, Print, Nil, Return
+        for _ in 0..5 {
+            assert_eq!(vm.step().unwrap(), StepResult::Paused);
+        }
+        assert_eq!(vm.step().unwrap(), StepResult::Finished);
+        assert_eq!(vm.step().unwrap(), StepResult::Finished);
+        assert!(vm.load("print -nil;").is_ok());
+        assert_eq!(vm.step().unwrap(), StepResult::Paused);
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn generators() {
         let test = "
-        print \"x\" == \"x\";
+        fun count(n) {
+            for (var i = 1; i <= n; i = i + 1) yield i;
+            return \"done\";
+        }
+        var g = count(3);
+        var a = g(); var b = g(); var c = g();
+        var d = g(); var e = g();
+        var h = count(1);
+        var f = h();
         ";
         let mut vm = VM::new(Heap::new());
         let result = vm.interpret(test);
         assert!(result.is_ok(), "{}", result.unwrap_err());
+        let done = Value::from(vm.heap.intern_copy("done"));
+        let expected = [
+            ("a", Value::from(1.0)),
+            ("b", Value::from(2.0)),
+            ("c", Value::from(3.0)),
+            ("d", done),
+            ("e", Value::Nil),
+            ("f", Value::from(1.0)),
+        ];
+        for (name, value) in expected {
+            assert_eq!(global(&mut vm, name), Some(value), "{}", name);
+        }
+    }
+
+    #[test]
+    fn failed_generator_is_done() {
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret("fun fail() { yield 1; yield -nil; } var g = fail(); g(); g();");
+        assert!(matches!(result, Err(InterpretError::Runtime(_))));
+        let result = vm.interpret("var after = g();");
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(global(&mut vm, "after"), Some(Value::Nil));
+    }
+
+    #[test]
+    fn generator_shares_captured_locals() {
+        let test = "
+        var get;
+        var set;
+        fun counter() {
+            var n = 0;
+            fun read() { return n; }
+            fun write(m) { n = m; }
+            get = read;
+            set = write;
+            yield n;
+            n = n + 1;
+            yield n;
+            n = n + 1;
+            yield n;
+        }
+        var g = counter();
+        var a = g(); var x = get();
+        var b = g(); var y = get();
+        set(10);
+        var c = g(); var z = get();
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        for (name, expected) in [
+            ("a", 0.0),
+            ("x", 0.0),
+            ("b", 1.0),
+            ("y", 1.0),
+            ("c", 11.0),
+            ("z", 11.0),
+        ] {
+            assert_eq!(
+                global(&mut vm, name),
+                Some(Value::from(expected)),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(vm.heap.validate(), Ok(()));
+    }
+
+    #[test]
+    fn valid_heap_after_collection() {
+        let test = "
+        class A {
+            init(x) {
+                fun f() { return x; }
+                this.f = f;
+            }
+        }
+        var a = A(\"x\");
+        var g = a.f;
+        for (var i = 0; i < 10; i = i + 1) A(i);
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(vm.heap.validate(), Ok(()));
+        let roots = vm.roots();
+        vm.heap.retain(roots);
+        assert_eq!(vm.heap.validate(), Ok(()));
+    }
+
+    #[test]
+    fn unwind_frames() {
+        let test = "
+        var get;
+        fun top() {
+            var a = 1;
+            fun inner() {
+                var b = 2;
+                fun g() { return a + b; }
+                get = g;
+                while (true) {}
+            }
+            inner();
+        }
+        top();
+        ";
+        let mut vm = VM::new(Heap::new());
+        assert!(vm.load(test).is_ok());
+        let mut steps = 0;
+        while Closure::nullable(global(&mut vm, "get").unwrap_or(Value::Nil)).is_none() {
+            assert!(steps < 1000);
+            assert!(matches!(vm.step(), Ok(StepResult::Paused)));
+            steps += 1;
+        }
+        assert_eq!(vm.frames.len(), 3);
+        let slots = vm.frames[1].slots;
+        vm.unwind_to(1);
+        assert_eq!(vm.frames.len(), 1);
+        assert_eq!(vm.stack_top, slots);
+        assert!(vm.open_upvalues.is_none());
+        let g = Closure::nullable(global(&mut vm, "get").unwrap()).unwrap();
+        let values: Vec<Value> = g
+            .upvalues
+            .iter()
+            .map(|upvalue| match **upvalue {
+                Upvalue::Closed(value) => value,
+                Upvalue::Open(..) => panic!("open upvalue"),
+            })
+            .collect();
+        assert_eq!(values, [Value::from(1.0), Value::from(2.0)]);
+    }
+
+    #[test]
+    fn modulo() {
+        let test = "
+        print 7 % 3;
+        var a = 7 % 3;
+        var b = -7 % 3;
+        var c = 7.5 % 2;
+        var d = 1 + 5 % 3 * 2;
+        var e = 1 % 0;
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(global(&mut vm, "a"), Some(Value::from(1.0)));
+        assert_eq!(global(&mut vm, "b"), Some(Value::from(-1.0)));
+        assert_eq!(global(&mut vm, "c"), Some(Value::from(1.5)));
+        assert_eq!(global(&mut vm, "d"), Some(Value::from(5.0)));
+        assert!(matches!(global(&mut vm, "e"), Some(Value::Number(e)) if e.is_nan()));
+        assert!(vm.interpret("print 1 % nil;").is_err());
+    }
+
+    #[test]
+    fn nan_comparisons() {
+        let test = "
+        var nan = 0 / 0;
+        var a = nan < 1;
+        var b = 1 < nan;
+        var c = nan > 1;
+        var d = 1 > nan;
+        var e = nan < nan;
+        var f = nan > nan;
+        var g = nan <= 1;
+        var h = 1 <= nan;
+        var i = nan >= 1;
+        var j = 1 >= nan;
+        var k = nan <= nan;
+        var l = nan >= nan;
+        var m = (1 <= 1) == (1 >= 1);
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        for name in ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l"] {
+            assert_eq!(global(&mut vm, name), Some(Value::False), "{}", name);
+        }
+        assert_eq!(global(&mut vm, "m"), Some(Value::True));
+    }
+
+    #[test]
+    fn truthiness() {
+        let test = "
+        var zero = !0;
+        var empty = !\"\";
+        var nil_ = !nil;
+        var false_ = !false;
+        var branch = 0 or \"\" or \"taken\";
+        ";
+        let mut vm = VM::new(Heap::new());
+        assert!(vm.interpret(test).is_ok());
+        let taken = Value::from(vm.heap.intern_copy("taken"));
+        assert_eq!(global(&mut vm, "zero"), Some(Value::False));
+        assert_eq!(global(&mut vm, "empty"), Some(Value::False));
+        assert_eq!(global(&mut vm, "nil_"), Some(Value::True));
+        assert_eq!(global(&mut vm, "false_"), Some(Value::True));
+        assert_eq!(global(&mut vm, "branch"), Some(Value::from(0.0)));
+        vm.set_truthiness(TruthinessMode::Loose);
+        assert!(vm.interpret(test).is_ok());
+        assert_eq!(global(&mut vm, "zero"), Some(Value::True));
+        assert_eq!(global(&mut vm, "empty"), Some(Value::True));
+        assert_eq!(global(&mut vm, "nil_"), Some(Value::True));
+        assert_eq!(global(&mut vm, "false_"), Some(Value::True));
+        assert_eq!(global(&mut vm, "branch"), Some(taken));
+    }
+
+    #[test]
+    fn profiling() {
+        let test = "
+        fun fib(n) {
+            if (n < 2) return n;
+            return fib(n - 2) + fib(n - 1);
+        }
+        print fib(10);
+        ";
+        let mut vm = VM::new(Heap::new());
+        assert!(vm.interpret(test).is_ok());
+        assert_eq!(vm.op_counts(), [0; OP_COUNT]);
+        vm.enable_profiling();
+        assert!(vm.interpret(test).is_ok());
+        let op_counts = vm.op_counts();
+        // fib(10) makes 177 calls, 88 of which recurse
+        assert_eq!(op_counts[Op::Call as usize], 177);
+        assert_eq!(op_counts[Op::Add as usize], 88);
+        assert_eq!(op_counts[Op::Subtract as usize], 2 * 88);
+        assert_eq!(op_counts[Op::Print as usize], 1);
+    }
+
+    #[test]
+    fn runtime_error_message() {
+        let mut vm = VM::new(Heap::new());
+        match vm.interpret("fun f() { return true + nil; }\nf();") {
+            Err(InterpretError::Runtime(msg)) => {
+                assert!(msg.starts_with("Error: Operands must be"), "{}", msg);
+                assert!(msg.contains("[line 1, col 25]"), "{}", msg);
+                assert!(msg.contains("[line 2, col 3]"), "{}", msg);
+            }
+            _ => panic!("expected a runtime error"),
+        }
+        match vm.interpret("var x = 1;\nprint x.y;") {
+            Err(InterpretError::Runtime(msg)) => {
+                assert!(msg.ends_with("at <script> [line 2, col 9]"), "{}", msg)
+            }
+            _ => panic!("expected a runtime error"),
+        }
+        assert!(matches!(
+            vm.interpret("print;"),
+            Err(InterpretError::Compile(_))
+        ));
+    }
+
+    #[cfg(feature = "debug_info")]
+    #[test]
+    fn locals_in_runtime_error() {
+        let test = "fun f(n) {
+            var i = 3;
+            { var j = 4; }
+            return i + nil;
+        }
+        f(2);";
+        let mut vm = VM::new(Heap::new());
+        match vm.interpret(test) {
+            Err(InterpretError::Runtime(msg)) => {
+                assert!(msg.contains("local n = 2"), "{}", msg);
+                assert!(msg.contains("local i = 3"), "{}", msg);
+                assert!(!msg.contains("local j"), "{}", msg);
+            }
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn disabled_output_keeps_side_effects() {
+        let test = "
+        var count = 0;
+        fun increment() {
+            count = count + 1;
+            return count;
+        }
+        print increment();
+        print increment();
+        ";
+        let mut vm = VM::new(Heap::new());
+        vm.set_output_enabled(false);
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(global(&mut vm, "count"), Some(Value::from(2.0)));
+    }
+
+    #[test]
+    fn method_on_non_class() {
+        let mut vm = VM::new(Heap::new());
+        let name = Value::from(vm.heap.intern_copy("m"));
+        let mut function = Function::new(None);
+        function.chunk.write(&[Op::Nil as u8, Op::Nil as u8], 1);
+        let index = function.chunk.add_constant(name).unwrap();
+        function.chunk.write_byte_op(Op::Method, index, 1);
+        let function = vm.heap.store(function);
+        match vm.run_function(function) {
+            Err(InterpretError::Runtime(msg)) => {
+                assert!(msg.starts_with("Error: Cannot define method 'm' on 'nil'."))
+            }
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn introspection() {
+        let mut vm = VM::new(Heap::new());
+        let test = "class Foo { bar() {} }
+        var same_class = class_of(Foo()) == Foo;
+        var class_name = name_of(Foo);
+        var method_name = name_of(Foo().bar);";
+        assert!(vm.interpret(test).is_ok());
+        assert_eq!(global(&mut vm, "same_class"), Some(Value::True));
+        let foo = Value::from(vm.heap.intern_copy("Foo"));
+        assert_eq!(global(&mut vm, "class_name"), Some(foo));
+        let bar = Value::from(vm.heap.intern_copy("bar"));
+        assert_eq!(global(&mut vm, "method_name"), Some(bar));
+        // arity and types are checked before the natives run
+        for (source, message) in [
+            (
+                "class_of(Foo);",
+                "class_of expects an instance for argument 1",
+            ),
+            ("class_of();", "class_of expects 1 argument but got 0"),
+            ("name_of(Foo, Foo);", "name_of expects 1 argument but got 2"),
+            ("name_of(1);", "Only classes and functions have names"),
+        ] {
+            let Err(InterpretError::Runtime(trace)) = vm.interpret(source) else {
+                panic!("no runtime error for {}", source);
+            };
+            assert!(trace.contains(message), "{}", trace);
+        }
+    }
+
+    #[test]
+    fn get_this() {
+        let test = "
+        class A {
+            init(x) { this.x = x; }
+            get() { return this.x + this.y(); }
+            y() { fun z() { return this; } return z().x; }
+        }
+        var a = A(2).get();
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(global(&mut vm, "a"), Some(Value::from(4.0)));
+    }
+
+    #[test]
+    fn invoke_this() {
+        let test = "
+        class A {
+            init() { this.count = 0; }
+            add(a, b) { this.count = this.count + 1; return a - b; }
+            twice(x) { return this.add(x, 1) + this.add(2 * x, 3); }
+            field() { return this.f(7, 2); }
+        }
+        var a = A();
+        var b = a.twice(5);
+        a.f = a.add;
+        var c = a.field();
+        var d = a.count;
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(global(&mut vm, "b"), Some(Value::from(11.0)));
+        assert_eq!(global(&mut vm, "c"), Some(Value::from(5.0)));
+        assert_eq!(global(&mut vm, "d"), Some(Value::from(3.0)));
+        assert!(vm
+            .interpret("class B { m() { return this.n(); } } B().m();")
+            .is_err());
+    }
+
+    #[test]
+    fn typed_additions() {
+        let test = "
+        var a = 1 + 2;
+        var b = \"a\" + \"b\";
+        var c = -a + 0.5;
+        var d = b + \"{a}\";
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(global(&mut vm, "a"), Some(Value::from(3.0)));
+        let b = Value::from(vm.heap.intern_copy("ab"));
+        assert_eq!(global(&mut vm, "b"), Some(b));
+        assert_eq!(global(&mut vm, "c"), Some(Value::from(-2.5)));
+        let d = Value::from(vm.heap.intern_copy("ab3"));
+        assert_eq!(global(&mut vm, "d"), Some(d));
+        // wrong guesses give the errors of the generic addition
+        for (test, found) in [
+            ("print \"a\" + 1;", "'a' and '1'"),
+            ("print 1 + nil;", "'1' and 'nil'"),
+            ("print true + 123;", "'true' and '123'"),
+        ] {
+            let Err(InterpretError::Runtime(trace)) = vm.interpret(test) else {
+                panic!("no runtime error for {}", test);
+            };
+            let expected = format!(
+                "Operands must be either numbers or strings, found {}",
+                found
+            );
+            assert!(trace.contains(&expected), "{}", trace);
+        }
+    }
+
+    #[test]
+    fn format_native() {
+        let mut vm = VM::new(Heap::new());
+        let shown = |vm: &mut VM, source| vm.eval(source).map(|value| value.to_string());
+        let test = "format(\"{}+{}={}\", 1, 2, 3);";
+        assert_eq!(shown(&mut vm, test).unwrap(), "1+2=3");
+        let test = "format(\"{{ {} }} {{{{}}}}\", nil);";
+        assert_eq!(shown(&mut vm, test).unwrap(), "{ nil } {}");
+        let test = "format(\"{{x: {}}}\", \"{}\");";
+        assert_eq!(shown(&mut vm, test).unwrap(), "{x: {}}");
+        assert_eq!(shown(&mut vm, "format(\"none\");").unwrap(), "none");
+        for error in [
+            "format();",
+            "format(1);",
+            "format(\"{}\");",
+            "format(\"{}\", 1, 2);",
+            "format(\"{{\", 1);",
+        ] {
+            assert!(
+                matches!(vm.eval(error), Err(InterpretError::Runtime(_))),
+                "{}",
+                error
+            );
+        }
+        assert!(matches!(vm.eval("printf(\"{}!\", 1);"), Ok(Value::Nil)));
+        vm.set_output_enabled(false);
+        assert!(matches!(vm.eval("printf(\"{}!\", 1);"), Ok(Value::Nil)));
+        assert!(vm.eval("printf(\"{}!\");").is_err());
+    }
+
+    #[test]
+    fn eval() {
+        let mut vm = VM::new(Heap::new());
+        let result = vm.eval("var a = 2; a * 3;");
+        assert!(matches!(result, Ok(value) if value == Value::from(6.0)));
+        let result = vm.eval("\"a = {a}\";").map(|value| value.to_string());
+        assert_eq!(result.unwrap(), "a = 2");
+        assert!(matches!(vm.eval("a = 4;"), Ok(value) if value == Value::from(4.0)));
+        assert!(matches!(vm.eval("var b = a;"), Ok(Value::Nil)));
+        assert!(matches!(vm.eval("if (a > 3) a; else nil;"), Ok(Value::Nil)));
+        assert!(matches!(vm.eval("{ a + 1; }"), Ok(value) if value == Value::from(5.0)));
+        assert!(matches!(vm.eval("a +"), Err(InterpretError::Compile(_))));
+        // nothing is left on the stack
+        assert_eq!(vm.stack_top, 0);
+        assert!(vm.interpret("print a;").is_ok());
+        assert_eq!(vm.stack_top, 0);
+    }
+
+    #[test]
+    fn lists() {
+        let test = "
+        var list = [1, \"two\", [3]];
+        var a = list[0] + list[2][0];
+        list[1] = list[1] + \"!\";
+        var b = list[1];
+        var c = [];
+        list[2][0] = list;
+        var shown = \"{list}\";
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(global(&mut vm, "a"), Some(Value::from(4.0)));
+        let b = Value::from(vm.heap.intern_copy("two!"));
+        assert_eq!(global(&mut vm, "b"), Some(b));
+        let c = List::nullable(global(&mut vm, "c").unwrap()).unwrap();
+        assert!(c.items.is_empty());
+        let shown = global(&mut vm, "shown").unwrap().to_string();
+        assert!(shown.starts_with("[1, two!, [[1, two!, [["), "{}", shown);
+        assert!(shown.contains("[...]"), "{}", shown);
+
+        for error in ["[1][1];", "[1][-1];", "[1][0.5];", "[1][nil];", "nil[0];"] {
+            assert!(matches!(
+                vm.interpret(error),
+                Err(InterpretError::Runtime(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn math_natives() {
+        let mut vm = VM::new(Heap::new());
+        for (source, expected) in [
+            ("sqrt(9);", 3.0),
+            ("floor(-1.5);", -2.0),
+            ("ceil(1.2);", 2.0),
+            ("abs(-4);", 4.0),
+            ("pow(2, 10);", 1024.0),
+            ("min(3, -3);", -3.0),
+            ("max(3, -3);", 3.0),
+        ] {
+            let result = vm.eval(source);
+            assert!(
+                result.is_ok_and(|value| value == Value::from(expected)),
+                "{}",
+                source
+            );
+        }
+        for error in ["sqrt();", "pow(2);", "abs(\"a\");", "min(1, nil);"] {
+            assert!(matches!(vm.eval(error), Err(InterpretError::Runtime(_))));
+        }
+    }
+
+    #[test]
+    fn native_arg_types() {
+        let mut vm = VM::new(Heap::new());
+        assert!(vm.eval("sqrt(4);").is_ok_and(|x| x == Value::from(2.0)));
+        for (source, expected) in [
+            (
+                "sqrt(\"x\");",
+                "sqrt expects a number for argument 1, got string.",
+            ),
+            (
+                "pow(2, nil);",
+                "pow expects a number for argument 2, got nil.",
+            ),
+            ("sqrt(1, 2);", "sqrt expects 1 argument but got 2."),
+            ("clock(1);", "clock expects 0 arguments but got 1."),
+        ] {
+            match vm.eval(source) {
+                Err(InterpretError::Runtime(msg)) => {
+                    assert!(msg.starts_with(&format!("Error: {}", expected)), "{}", msg)
+                }
+                _ => panic!("expected a runtime error from {}", source),
+            }
+        }
+    }
+
+    fn len_native(_heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+        let length = match (Loxtr::nullable(args[0]), List::nullable(args[0])) {
+            (Some(string), _) => string.as_ref().chars().count(),
+            (_, Some(list)) => list.items.len(),
+            _ => return err!("Only strings and lists have a length."),
+        };
+        Ok(Value::from(length as f64))
+    }
+
+    fn repeat_native(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+        let [string, count] = args else {
+            return err!("Expected 2 arguments but got {}.", args.len());
+        };
+        let count = f64::try_from(*count)? as usize;
+        Ok(Value::from(heap.intern(string.to_string().repeat(count))))
+    }
+
+    #[test]
+    fn register_native() {
+        let mut vm = VM::new(Heap::new());
+        vm.register_native("len", len_native, Some(&[ArgType::Any]));
+        vm.register_native("repeat", repeat_native, None);
+        let test = "
+        var a = len(\"héllo\") + len([1, 2]);
+        var b = repeat(\"ab\", 3);
+        ";
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(global(&mut vm, "a"), Some(Value::from(7.0)));
+        let b = Value::from(vm.heap.intern_copy("ababab"));
+        assert_eq!(global(&mut vm, "b"), Some(b));
+
+        vm.reset_globals();
+        assert!(vm.eval("len(nil);").is_err());
+        assert!(vm.eval("len(\"\");").is_ok_and(|x| x == Value::from(0.0)));
+    }
+
+    #[test]
+    fn shadowed_builtins() {
+        let mut vm = VM::new(Heap::new());
+        match vm.interpret("clock = 1;") {
+            Err(InterpretError::Runtime(msg)) => {
+                assert!(
+                    msg.starts_with("Error: Cannot assign to builtin 'clock'"),
+                    "{}",
+                    msg
+                )
+            }
+            _ => panic!("expected a runtime error"),
+        }
+        assert!(vm
+            .eval("var clock = 1; clock = clock + 1; clock;")
+            .is_ok_and(|x| x == Value::from(2.0)));
+        vm.reset_globals();
+        assert!(vm
+            .eval("clock();")
+            .is_ok_and(|x| matches!(x, Value::Number(_))));
+    }
+
+    #[test]
+    fn long_jumps() {
+        let body = "x = x + 1;".repeat(10000);
+        let test = format!(
+            "fun f(n) {{
+                var x = 0;
+                var i = 0;
+                while (i < n) {{
+                    i = i + 1;
+                    if (x >= 0) {{ {body} }} else {{ {body} }}
+                }}
+                return x;
+            }}
+            var y = f(2);"
+        );
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(&test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let y = global(&mut vm, "y");
+        assert_eq!(y, Some(Value::from(20000.0)));
+
+        let f = global(&mut vm, "f").unwrap();
+        let chunk = &Closure::nullable(f).unwrap().function.chunk;
+        assert_eq!(chunk.verify(1), Ok(()));
+        let ops: Vec<u8> = chunk
+            .instruction_offsets()
+            .into_iter()
+            .map(|offset| chunk.read_byte(offset))
+            .collect();
+        for op in [Op::JumpLong, Op::JumpIfFalseLong, Op::LoopLong] {
+            assert!(ops.contains(&(op as u8)), "{:?}", op);
+        }
+    }
+
+    #[test]
+    fn switch_statement() {
+        let test = "
+        fun describe(x) {
+            var prefix = \"a \";
+            switch (x) {
+                case 1:
+                    var name = \"one\";
+                    return prefix + name;
+                case \"t\" + \"wo\":
+                    return prefix + \"string\";
+                case 3:
+                    prefix = \"three \";
+                default:
+                    return prefix + \"other\";
+            }
+        }
+        var a = describe(1);
+        var b = describe(\"two\");
+        var c = describe(3);
+        var d = describe(nil);
+        var e = 0;
+        switch (e) { case 0: e = 1; case 1: e = 2; }
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        for (name, expected) in [("a", "a one"), ("b", "a string"), ("d", "a other")] {
+            let value = Value::from(vm.heap.intern_copy(expected));
+            assert_eq!(global(&mut vm, name), Some(value), "{}", name);
+        }
+        // no fall through to the default
+        let c = global(&mut vm, "c");
+        assert_eq!(c, Some(Value::Nil));
+        let e = global(&mut vm, "e");
+        assert_eq!(e, Some(Value::from(1.0)));
+        assert_eq!(vm.stack_top, 0);
+        assert!(vm
+            .check("switch (1) { default: print 1; case 1: print 2; }")
+            .is_err());
+    }
+
+    #[test]
+    fn deep_equality() {
+        let test = "
+        class P { init(x, y) { this.x = x; this.y = y; } }
+        class Q { init(x, y) { this.x = x; this.y = y; } }
+        var p = P(1, P(\"a\", nil));
+        var q = P(1, P(\"a\", nil));
+        var deep = p === q;
+        var same = p == q;
+        var other = p === P(1, P(\"b\", nil));
+        var classes = P(1, 2) === Q(1, 2);
+        var numbers = 1 === 1 and !(1 === 2);
+
+        var a = P(1, nil);
+        var b = P(1, nil);
+        a.y = b;
+        b.y = a;
+        var cycle = a === b;
+        b.x = 2;
+        var broken = a === b;
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        for (name, expected) in [
+            ("deep", true),
+            ("same", false),
+            ("other", false),
+            ("classes", false),
+            ("numbers", true),
+            ("cycle", true),
+            ("broken", false),
+        ] {
+            assert_eq!(
+                global(&mut vm, name),
+                Some(Value::from(expected)),
+                "{}",
+                name
+            );
+        }
+
+        let long = "
+        class N { init(n) { this.n = n; } }
+        var a = nil;
+        var b = nil;
+        for (var i = 0; i < 50000; i = i + 1) { a = N(a); b = N(b); }
+        var chains = a === b;
+        ";
+        let result = vm.interpret(long);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(global(&mut vm, "chains"), Some(Value::True));
+    }
+
+    #[test]
+    fn string_methods() {
+        let test = "
+        var length = \"hello\".length;
+        var sub = \"hello\".substring(1, 3);
+        var upper = \"hello\".toUpper();
+        var lower = \"HeLLo\".toLower();
+        var empty = \"hello\".substring(5, 5);
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let expected = [
+            ("length", Value::from(5.0)),
+            ("sub", Value::from(vm.heap.intern_copy("el"))),
+            ("upper", Value::from(vm.heap.intern_copy("HELLO"))),
+            ("lower", Value::from(vm.heap.intern_copy("hello"))),
+            ("empty", Value::from(vm.heap.intern_copy(""))),
+        ];
+        for (name, value) in expected {
+            assert_eq!(global(&mut vm, name), Some(value), "{}", name);
+        }
+
+        for source in [
+            "\"hello\".substring(2, 6);",
+            "\"hello\".substring(-1, 2);",
+            "\"hello\".substring(3, 2);",
+            "\"hello\".substring(1);",
+            "\"hello\".substring(\"1\", 2);",
+            "\"hello\".size;",
+            "\"hello\".reverse();",
+        ] {
+            assert!(vm.interpret(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn getters() {
+        let test = "
+        class Rect {
+            init(w, h) { this.w = w; this.h = h; }
+            area { return this.w * this.h; }
+            double() { return this.area * 2; }
+        }
+        class Square < Rect {
+            init(s) { super.init(s, s); }
+            area { return super.area + 0; }
+            adder {
+                fun add(x) { return this.w + x; }
+                return add;
+            }
+            plus(x) { return this.adder(x); }
+        }
+        class Cube < Square {
+            plus(x) { return super.adder(x) * 2; }
+        }
+        var rect = Rect(2, 3);
+        var area = rect.area;
+        var double = rect.double();
+        var square = Square(4).area;
+        var added = (Square(1).adder)(2);
+        var invoked = Square(1).adder(2);
+        var this_invoked = Square(2).plus(3);
+        var super_invoked = Cube(2).plus(3);
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        for (name, expected) in [
+            ("area", 6.0),
+            ("double", 12.0),
+            ("square", 16.0),
+            ("added", 3.0),
+            ("invoked", 3.0),
+            ("this_invoked", 5.0),
+            ("super_invoked", 10.0),
+        ] {
+            assert_eq!(
+                global(&mut vm, name),
+                Some(Value::from(expected)),
+                "{}",
+                name
+            );
+        }
+
+        assert!(vm.interpret("rect.area = 1;").is_err());
+        assert!(vm.interpret("class A { init { } }").is_err());
+    }
+
+    #[test]
+    fn static_methods() {
+        let test = "
+        class Math {
+            static square(x) { return x * x; }
+            static cube(x) { return x * Math.square(x); }
+        }
+        class Sub < Math {
+            static square(x) { return 0; }
+        }
+        var square = Math.square(4);
+        var cube = Math.cube(2);
+        var f = Math.square;
+        var called = f(3);
+        var inherited = Sub.cube(2);
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        for (name, expected) in [
+            ("square", 16.0),
+            ("cube", 8.0),
+            ("called", 9.0),
+            ("inherited", 8.0),
+        ] {
+            assert_eq!(
+                global(&mut vm, name),
+                Some(Value::from(expected)),
+                "{}",
+                name
+            );
+        }
+
+        assert!(vm.interpret("Math().square(2);").is_err());
+        assert!(vm.interpret("Math.missing(2);").is_err());
+        for error in [
+            "class A { static f() { return this; } }",
+            "class B { static f() { fun g() { return this; } return g; } }",
+            "class C < A { static f() { fun g() { return super.f; } return g; } }",
+        ] {
+            assert!(
+                matches!(vm.interpret(error), Err(InterpretError::Compile(_))),
+                "{}",
+                error
+            );
+        }
+        // a class in a static method has methods of its own
+        let test = "
+        class D {
+            static f() {
+                class E { g() { fun h() { return this; } return h(); } }
+                return E().g();
+            }
+        }";
+        assert!(vm.interpret(test).is_ok());
+    }
+
+    #[test]
+    fn inherit_without_copying_methods() {
+        let mut test = String::from("class A {");
+        for i in 0..20 {
+            test.push_str(&format!(" m{}() {{ return {}; }}", i, i));
+        }
+        test.push_str(" }");
+        for i in 0..100 {
+            test.push_str(&format!(" class B{} < A {{}}", i));
+        }
+        test.push_str(" class C < B42 { m0() { return super.m0() - 1; } }");
+        test.push_str(" var inherited = B99().m19(); var overridden = C().m0();");
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(&test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        for (name, expected) in [("inherited", 19.0), ("overridden", -1.0)] {
+            assert_eq!(
+                global(&mut vm, name),
+                Some(Value::from(expected)),
+                "{}",
+                name
+            );
+        }
+
+        let class = |vm: &mut VM, name: &str| Class::nullable(global(vm, name).unwrap()).unwrap();
+        // copying would cost every subclass at least the table of the base class
+        let base = class(&mut vm, "A").methods.capacity();
+        let subclasses: usize = (0..100)
+            .map(|i| class(&mut vm, &format!("B{}", i)).methods.capacity())
+            .sum();
+        assert!(base >= 20);
+        assert!(
+            subclasses * 10 < base * 100,
+            "{} vs {}",
+            subclasses,
+            base * 100
+        );
+    }
+
+    #[test]
+    fn default_parameters() {
+        let test = "
+        fun greet(name, greeting = \"Hello\", end = greeting + \"!\") {
+            return greeting + \", \" + name + end;
+        }
+        var a = greet(\"a\");
+        var b = greet(\"b\", \"Hi\");
+        var c = greet(\"c\", \"Hi\", \".\");
+        fun count(to = 2) { for (var i = 0; i < to; i = i + 1) yield i; }
+        var gen = count();
+        gen();
+        var d = gen();
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let expected = [
+            ("a", Value::from(vm.heap.intern_copy("Hello, aHello!"))),
+            ("b", Value::from(vm.heap.intern_copy("Hi, bHi!"))),
+            ("c", Value::from(vm.heap.intern_copy("Hi, c."))),
+            ("d", Value::from(1.0)),
+        ];
+        for (name, value) in expected {
+            assert_eq!(global(&mut vm, name), Some(value), "{}", name);
+        }
+
+        assert!(vm.interpret("greet();").is_err());
+        assert!(vm.interpret("greet(1, 2, 3, 4);").is_err());
+        assert!(vm.interpret("fun f(a = 1, b) {}").is_err());
+        assert!(vm.interpret("fun g(a = a) {}").is_err());
+    }
+
+    #[test]
+    fn tail_calls() {
+        let test = "
+        fun count(n, acc) {
+            if (n == 0) return acc;
+            return count(n - 1, acc + 1);
+        }
+        var counted = count(10000, 0);
+        fun even(n) { if (n == 0) return true; return odd(n - 1); }
+        fun odd(n) { if (n == 0) return false; return even(n - 1); }
+        var parity = even(1001);
+        fun make(n) {
+            fun inner() { return n; }
+            if (n > 0) return make(n - 1) + inner();
+            return inner();
+        }
+        var captured = make(3);
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let expected = [
+            ("counted", Value::from(10000.0)),
+            ("parity", Value::False),
+            ("captured", Value::from(6.0)),
+        ];
+        for (name, value) in expected {
+            assert_eq!(global(&mut vm, name), Some(value), "{}", name);
+        }
+        // calls that are not in tail position still use a frame each
+        assert!(vm
+            .interpret("fun f(n) { if (n == 0) return 0; return 1 + f(n - 1); } f(10000);")
+            .is_err());
+    }
+
+    #[test]
+    fn frame_limit() {
+        let test = "fun depth(n) { if (n == 0) return 0; return 1 + depth(n - 1); }";
+        let mut vm = VM::new(Heap::new());
+        assert!(vm.interpret(test).is_ok());
+        assert!(vm.interpret("var deep = depth(500);").is_ok());
+        assert_eq!(global(&mut vm, "deep"), Some(Value::from(500.0)));
+
+        vm.set_max_frames(16);
+        assert!(vm.interpret("depth(10);").is_ok());
+        assert!(vm.interpret("depth(20);").is_err());
+        vm.set_max_frames(4096);
+        assert!(vm.interpret("depth(4000);").is_ok());
+    }
+
+    #[test]
+    fn power() {
+        let test = "
+        var right = 2 ** 3 ** 2;
+        var grouped = (2 ** 3) ** 2;
+        var factor = 2 * 3 ** 2;
+        var negated = -2 ** 2;
+        var negative_base = (-2) ** 3;
+        var negative_exponent = 2 ** -1;
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        for (name, expected) in [
+            ("right", 512.0),
+            ("grouped", 64.0),
+            ("factor", 18.0),
+            ("negated", -4.0),
+            ("negative_base", -8.0),
+            ("negative_exponent", 0.5),
+        ] {
+            assert_eq!(
+                global(&mut vm, name),
+                Some(Value::from(expected)),
+                "{}",
+                name
+            );
+        }
+        assert!(vm.interpret("2 ** nil;").is_err());
+    }
+
+    #[test]
+    fn closures_under_stress_gc() {
+        // collect before every allocation
+        let mut heap = Heap::with_threshold(0);
+        heap.set_growth_factor(1.0);
+        let test = "
+        var g = \"g\";
+        fun outer(p) {
+            var a = \"a\" + p;
+            var b = \"b\" + p;
+            var c = \"c\" + p;
+            fun middle() {
+                var d = \"d\" + p;
+                fun inner() { return a + b + c + d + g; }
+                a = a + \"!\";
+                return inner;
+            }
+            // not a tail call, so middle's upvalues get closed on return
+            var m = middle();
+            return m;
+        }
+        var closures = [];
+        for (var i = 0; i < 3; i = i + 1) {
+            fun f() { return i; }
+            closures = [f, outer(\"2\")];
+        }
+        var result = closures[1]();
+        var last = closures[0]();
+        ";
+        let mut vm = VM::new(heap);
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let expected = Value::from(vm.heap.intern_copy("a2!b2c2d2g"));
+        assert_eq!(global(&mut vm, "result"), Some(expected));
+        assert_eq!(global(&mut vm, "last"), Some(Value::from(2.0)));
+        assert_eq!(vm.heap.validate(), Ok(()));
+    }
+
+    #[test]
+    fn computed_method_names() {
+        let test = "
+        var prefix = \"get\";
+        class A {
+            init(x) { this.x = x; }
+            [prefix + \"X\"]() { return this.x; }
+        }
+        var x = A(3).getX();
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let x = global(&mut vm, "x");
+        assert_eq!(x, Some(Value::from(3.0)));
+
+        for error in ["class B { [1]() {} }", "class C { [\"init\"]() {} }"] {
+            assert!(matches!(
+                vm.interpret(error),
+                Err(InterpretError::Runtime(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn conditional() {
+        let test = "
+        fun sign(x) { return x < 0 ? -1 : x == 0 ? 0 : 1; }
+        var a = sign(-3);
+        var b = sign(0);
+        var c = sign(5);
+        var d = nil ? 1 : 2 + 3;
+        var e;
+        var f = true ? e = 4 : 5;
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(global(&mut vm, "a"), Some(Value::from(-1.0)));
+        assert_eq!(global(&mut vm, "b"), Some(Value::from(0.0)));
+        assert_eq!(global(&mut vm, "c"), Some(Value::from(1.0)));
+        assert_eq!(global(&mut vm, "d"), Some(Value::from(5.0)));
+        assert_eq!(global(&mut vm, "e"), Some(Value::from(4.0)));
+        assert_eq!(global(&mut vm, "f"), Some(Value::from(4.0)));
+        assert!(matches!(
+            vm.interpret("print true ? 1;"),
+            Err(InterpretError::Compile(_))
+        ));
+    }
+
+    #[test]
+    fn cascade() {
+        let mut vm = VM::new(Heap::new());
+        let test = "class Point {}
+        var p = Point()..x = 1 + 2..y = 4;
+        var same = p == (p..x = 5);";
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(global(&mut vm, "same"), Some(Value::True));
+        let p = Instance::nullable(global(&mut vm, "p").unwrap()).unwrap();
+        let x = vm.heap.intern_copy("x");
+        let y = vm.heap.intern_copy("y");
+        assert_eq!(p.properties.get(x), Some(Value::from(5.0)));
+        assert_eq!(p.properties.get(y), Some(Value::from(4.0)));
+        assert!(vm.interpret("Point()..x;").is_err());
+    }
+
+    #[test]
+    fn has_property() {
+        let mut vm = VM::new(Heap::new());
+        let test = "class Foo { bar() {} }
+        var foo = Foo();
+        foo.x = nil;
+        var has_x = has_property(foo, \"x\");
+        var has_y = has_property(foo, \"y\");
+        var has_bar = has_property(foo, \"bar\");";
+        assert!(vm.interpret(test).is_ok());
+        assert_eq!(global(&mut vm, "has_x"), Some(Value::True));
+        assert_eq!(global(&mut vm, "has_y"), Some(Value::False));
+        assert_eq!(global(&mut vm, "has_bar"), Some(Value::False));
+        assert!(vm.interpret("has_property(Foo, \"x\");").is_err());
+        assert!(vm.interpret("has_property(foo, 1);").is_err());
+    }
+
+    #[test]
+    fn interned_strings() {
+        let mut vm = VM::new(Heap::new());
+        let before = vm.intern_count();
+        let test = "var greeting = \"hello\"; print greeting + \" world\";";
+        assert!(vm.interpret(test).is_ok());
+        let strings: Vec<&str> = vm.interned_strings().collect();
+        assert_eq!(strings.len(), vm.intern_count());
+        for expected in [
+            "greeting",
+            "hello",
+            " world",
+            "hello world",
+            "init",
+            "clock",
+        ] {
+            assert!(strings.contains(&expected), "missing '{}'", expected);
+        }
+        assert_eq!(vm.intern_count(), before + 4);
+    }
+
+    #[test]
+    fn string_equality() {
+        let test = "
+        print \"x\" == \"x\";
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+    }
+
+    // call_value finds initializers by the handle of the vm's init string
+    #[test]
+    fn init_string_is_shared() {
+        let mut vm = VM::new(Heap::new());
+        let init_string = vm.common_strings.init;
+        let init_of = |vm: &mut VM, class| {
+            let class = Class::nullable(global(vm, class)?)?;
+            let init = class.methods.keys().find(|key| key.as_ref() == "init");
+            init.copied()
+        };
+
+        assert!(vm.interpret("class A { init() { this.x = 1; } }").is_ok());
+        assert_eq!(init_of(&mut vm, "A"), Some(init_string));
+
+        vm.reset_globals();
+        vm.collect_garbage();
+        vm.collect_garbage();
+        assert_eq!(vm.common_strings.init, init_string);
+        assert_eq!(vm.heap.intern_copy("init"), init_string);
+        assert!(vm.interpret("class B { init() { this.x = 2; } }").is_ok());
+        assert_eq!(init_of(&mut vm, "B"), Some(init_string));
+        assert!(vm.eval("B().x;").is_ok_and(|x| x == Value::from(2.0)));
+    }
+
+    #[test]
+    fn common_strings() {
+        let mut vm = VM::new(Heap::new());
+        vm.collect_garbage();
+        let common = &vm.common_strings;
+        let cached = [
+            common.init,
+            common.str,
+            common.add,
+            common.iter,
+            common.next,
+        ];
+        for (name, handle) in ["init", "__str__", "__add__", "iter", "next"]
+            .into_iter()
+            .zip(cached)
+        {
+            assert_eq!(vm.heap.intern_copy(name), handle, "{}", name);
+        }
+    }
+
+    // a buffer the test can still read after handing it to the vm
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8_lossy(&self.0.borrow())
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    // records writes, flushes and reads in order
+    #[derive(Clone, Default)]
+    struct EventLog(std::rc::Rc<std::cell::RefCell<Vec<String>>>);
+
+    impl Write for EventLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let text = String::from_utf8_lossy(buf);
+            self.0.borrow_mut().push(format!("write {}", text));
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.borrow_mut().push(String::from("flush"));
+            Ok(())
+        }
+    }
+
+    struct LoggedInput(EventLog, io::Cursor<&'static str>);
+
+    impl io::Read for LoggedInput {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1.read(buf)
+        }
+    }
+
+    impl BufRead for LoggedInput {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            self.0 .0.borrow_mut().push(String::from("read"));
+            self.1.fill_buf()
+        }
+        fn consume(&mut self, amt: usize) {
+            self.1.consume(amt)
+        }
+    }
+
+    #[test]
+    fn flush_before_read() {
+        let log = EventLog::default();
+        let mut vm = VM::with_output(Heap::new(), Box::new(log.clone()));
+        vm.set_input(Box::new(LoggedInput(log.clone(), io::Cursor::new("42\n"))));
+        let result = vm.interpret("write(\"? \"); var answer = read(); var end = read();");
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let events = log.0.borrow();
+        let prompt = events.iter().position(|e| e == "write ? ").unwrap();
+        assert_eq!(events[prompt + 1..prompt + 3], ["flush", "read"]);
+
+        let expected = Value::from(vm.heap.intern_copy("42"));
+        assert_eq!(global(&mut vm, "answer"), Some(expected));
+        assert_eq!(global(&mut vm, "end"), Some(Value::Nil));
+    }
+
+    #[test]
+    fn printing_natives_share_the_output() {
+        let log = EventLog::default();
+        let mut vm = VM::with_output(Heap::new(), Box::new(log.clone()));
+        let result = vm.interpret("write(\"a\"); printf(\"b{}\", 1); print \"c\"; write(\"d\");");
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let written: String = log
+            .0
+            .borrow()
+            .iter()
+            .filter_map(|event| event.strip_prefix("write "))
+            .collect();
+        let (compiled, printed) = written.split_once('\n').unwrap();
+        assert!(compiled.starts_with("Compilation finished"));
+        assert_eq!(printed, "ab1\nc\nd");
+    }
+
+    #[test]
+    fn number_literals() {
+        let test = "
+        var hex = 0xFF;
+        var upper = 0XfF == 255;
+        var exponent = 1e3;
+        var fraction = 1.5e-3;
+        var signed = 2E+1;
+        var mixed = 0x10 * 1e1;
+        var wide = 0xFFFFFFFFFFFFFFFFFFFF == 2 ** 80;
+        ";
+        let mut vm = VM::new(Heap::new());
+        let result = vm.interpret(test);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        for (name, expected) in [
+            ("hex", Value::from(255.0)),
+            ("upper", Value::True),
+            ("exponent", Value::from(1000.0)),
+            ("fraction", Value::from(0.0015)),
+            ("signed", Value::from(20.0)),
+            ("mixed", Value::from(160.0)),
+            ("wide", Value::True),
+        ] {
+            assert_eq!(global(&mut vm, name), Some(expected), "{}", name);
+        }
+    }
+
+    #[test]
+    fn captured_warnings() {
+        let output = SharedBuffer::default();
+        let errors = SharedBuffer::default();
+        let mut vm = VM::with_output(Heap::new(), Box::new(output.clone()));
+        vm.set_error_output(Box::new(errors.clone()));
+        vm.set_strict(true);
+        assert!(vm.interpret("{ var unused = 1; }").is_ok());
+        assert!(vm.eval("{ var unused = 2; }").is_ok());
+        let warnings = errors.lines();
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+        assert!(warnings
+            .iter()
+            .all(|w| w.contains("Unused variable 'unused'.")));
+        assert!(output.lines().iter().all(|line| !line.contains("Unused")));
+    }
+
+    #[test]
+    fn captured_output() {
+        let output = SharedBuffer::default();
+        let errors = SharedBuffer::default();
+        let mut vm = VM::with_output(Heap::new(), Box::new(output.clone()));
+        vm.set_error_output(Box::new(errors.clone()));
+        let test = "print 1 + 2; print \"a\" + \"b\"; printf(\"{}-{}\", 1, 2);";
+        assert!(vm.interpret(test).is_ok());
+        let lines = output.lines();
+        assert!(lines[0].starts_with("Compilation finished"));
+        assert_eq!(lines[1..], ["3", "ab", "1-2"]);
+        assert!(errors.lines().is_empty());
+
+        assert!(vm.interpret("print -nil;").is_err());
+        assert_eq!(output.lines().len(), 5);
+        assert!(errors.lines()[0].starts_with("Error: Operand must be"));
+
+        let quiet = SharedBuffer::default();
+        vm.set_output(Box::new(quiet.clone()));
+        let printf = vm.heap.intern_copy("printf");
+        let builtin = vm.builtins.get(printf);
+        vm.set_output_enabled(false);
+        assert!(vm.interpret("print 4; printf(\"{}\", 5);").is_ok());
+        assert_eq!(quiet.lines().len(), 1);
+        // silencing output leaves the builtins alone
+        assert_eq!(vm.builtins.get(printf), builtin);
     }
 }